@@ -0,0 +1,206 @@
+use crate::logging::log;
+use crate::winapi::*;
+use crate::{
+    affinity_snapshot, describe_mask, get_exclude_mask, get_process_affinity_mask, parse_exclude_spec, print_topology,
+    restore_processor_affinity, set_control_pipe_mask, set_processor_affinity, shutting_down, AffinityContext,
+};
+use dll_proxy::winternals::GetLastError;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+
+// Commands are sent to the pipe one per line, and each one gets a single line back, starting with "ok" or "error". The
+// mask that set and restore leave is handed to the affinity thread, so reapply_interval doesn't undo them.
+//   set 0,1     Excludes cores 0 and 1 from all the cores on the system. Ranges like "0-3" work too.
+//   restore     Restores the affinity the process had before it was first changed.
+//   status      Replies with an AffinitySnapshot as JSON, and prints it and the system topology to the console and log
+//               file.
+#[derive(Debug, PartialEq)]
+enum ControlCommand {
+    Set(Vec<u32>),
+    Restore,
+    Status,
+}
+
+fn parse_control_command(line: &str) -> Result<ControlCommand, String> {
+    let line = line.trim();
+    let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    match command {
+        "set" => {
+            let mut cores = vec![];
+            for spec in args.split(',').filter(|spec| !spec.trim().is_empty()) {
+                cores.extend(parse_exclude_spec(spec).map_err(|e| e.to_string())?);
+            }
+            Ok(ControlCommand::Set(cores))
+        }
+        "restore" if args.trim().is_empty() => Ok(ControlCommand::Restore),
+        "status" if args.trim().is_empty() => Ok(ControlCommand::Status),
+        "restore" | "status" => Err(format!("{command} doesn't take any arguments")),
+        _ => Err(format!("Unknown command \"{command}\". Expected set, restore or status")),
+    }
+}
+
+fn run_control_command(context: &AffinityContext, command: ControlCommand) -> Result<String, String> {
+    match command {
+        // Exclude from every core on the system, rather than the current mask, so set can also add cores back. The
+        // system mask is used, rather than every bit, so there are no warnings about cores nobody asked for.
+        ControlCommand::Set(exclude) => {
+            let mask = set_processor_affinity(context, Some(context.system_affinity_mask()), get_exclude_mask(&exclude))
+                .map_err(|e| e.to_string())?;
+            set_control_pipe_mask(mask);
+            Ok(describe_mask(mask))
+        }
+        ControlCommand::Restore => {
+            restore_processor_affinity().map_err(|e| e.to_string())?;
+            if let Ok(mask) = get_process_affinity_mask(context) {
+                set_control_pipe_mask(mask);
+            }
+            Ok("restored".to_string())
+        }
+        ControlCommand::Status => {
+            print_topology();
+            let snapshot = affinity_snapshot().map_err(|e| e.to_string())?;
+            log!("{snapshot}");
+            serde_json::to_string(&snapshot).map_err(|e| e.to_string())
+        }
+    }
+}
+
+fn handle_line(context: &AffinityContext, line: &str) -> String {
+    match parse_control_command(line).and_then(|command| run_control_command(context, command)) {
+        Ok(reply) => format!("ok {reply}\n"),
+        Err(e) => format!("error {e}\n"),
+    }
+}
+
+// A pipe name without a path, like "affinity", is created as \\.\pipe\affinity.
+fn get_pipe_path(pipe_name: &str) -> String {
+    if pipe_name.starts_with(r"\\") {
+        return pipe_name.to_string();
+    }
+
+    format!(r"\\.\pipe\{pipe_name}")
+}
+
+pub fn spawn_control_pipe(pipe_name: &str, context: AffinityContext) {
+    let pipe_path = get_pipe_path(pipe_name);
+    std::thread::spawn(move || {
+        log!("Listening for commands on {pipe_path}");
+        // Only one client is served at a time. After it disconnects, the pipe is created again for the next one, unless
+        // the dll is being unloaded.
+        while !shutting_down() {
+            if let Err(e) = serve_client(&context, &pipe_path) {
+                log!("Control pipe error: {e}");
+                std::thread::sleep(std::time::Duration::from_secs(1));
+            }
+        }
+    });
+}
+
+fn serve_client(context: &AffinityContext, pipe_path: &str) -> std::io::Result<()> {
+    let name: Vec<u16> = pipe_path.encode_utf16().chain(std::iter::once(0)).collect();
+    let pipe = unsafe {
+        OwnedHandle::new(CreateNamedPipeW(
+            name.as_ptr(),
+            PIPE_ACCESS_DUPLEX,
+            // The pipe can change the affinity of the game, so only processes on this machine can use it.
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            1,
+            4096,
+            4096,
+            0,
+            std::ptr::null(),
+        ))
+    };
+    if !pipe.is_valid() {
+        return Err(last_error("Unable to create named pipe"));
+    }
+
+    if !unsafe { ConnectNamedPipe(*pipe, std::ptr::null_mut()) } && unsafe { GetLastError() } != ERROR_PIPE_CONNECTED {
+        return Err(last_error("Unable to connect named pipe"));
+    }
+
+    let mut writer = PipeStream(*pipe);
+    for line in BufReader::new(PipeStream(*pipe)).lines() {
+        let line = match line {
+            Ok(line) => line,
+            // A client sending something that isn't UTF-8 shouldn't take the pipe down.
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                writer.write_all(b"error Command is not valid UTF-8\n")?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        // The original affinity may already be restored, so a command now would change it back.
+        if shutting_down() {
+            writer.write_all(b"error The dll is being unloaded\n")?;
+            break;
+        }
+        writer.write_all(handle_line(context, &line).as_bytes())?;
+    }
+
+    unsafe { DisconnectNamedPipe(*pipe) };
+    Ok(())
+}
+
+fn last_error(message: &str) -> Error {
+    Error::other(format!("{message}. Last Error: {}", format_last_error(unsafe { GetLastError() })))
+}
+
+// Doesn't own the handle, so the reader and writer can share one pipe.
+struct PipeStream(HANDLE);
+
+impl Read for PipeStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut read = 0;
+        if !unsafe { ReadFile(self.0, buf.as_mut_ptr(), buf.len() as u32, &mut read, std::ptr::null_mut()) } {
+            // The client closing its end is the end of the stream.
+            if unsafe { GetLastError() } == ERROR_BROKEN_PIPE {
+                return Ok(0);
+            }
+            return Err(last_error("Unable to read from named pipe"));
+        }
+
+        Ok(read as usize)
+    }
+}
+
+impl Write for PipeStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut written = 0;
+        if !unsafe { WriteFile(self.0, buf.as_ptr(), buf.len() as u32, &mut written, std::ptr::null_mut()) } {
+            return Err(last_error("Unable to write to named pipe"));
+        }
+
+        Ok(written as usize)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_control_command() {
+    assert_eq!(parse_control_command("set 0,1"), Ok(ControlCommand::Set(vec![0, 1])));
+    assert_eq!(parse_control_command("set 0-2, 5"), Ok(ControlCommand::Set(vec![0, 1, 2, 5])));
+    assert_eq!(parse_control_command("set"), Ok(ControlCommand::Set(vec![])));
+    assert_eq!(parse_control_command(" restore "), Ok(ControlCommand::Restore));
+    assert_eq!(parse_control_command("status"), Ok(ControlCommand::Status));
+}
+
+#[test]
+fn test_parse_control_command_malformed() {
+    assert!(parse_control_command("").is_err());
+    assert!(parse_control_command("set a,b").is_err());
+    assert!(parse_control_command("set 3-1").is_err());
+    assert!(parse_control_command("status now").is_err());
+    assert!(parse_control_command("exclude 0").is_err());
+}
+
+#[test]
+fn test_get_pipe_path() {
+    assert_eq!(get_pipe_path("affinity"), r"\\.\pipe\affinity");
+    assert_eq!(get_pipe_path(r"\\.\pipe\custom"), r"\\.\pipe\custom");
+}
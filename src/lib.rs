@@ -1,78 +1,583 @@
 #![allow(non_snake_case)]
+// Without the proxy feature there is no DllMain, so the code that only it uses isn't called, but is still tested.
+#![cfg_attr(not(feature = "proxy"), allow(dead_code))]
 
+#[cfg(feature = "proxy")]
 use dll_proxy::proxy_dll;
-use dll_proxy::winternals::{GetLastError, GetModuleFileNameA, GetModuleHandleA};
-use std::ffi::c_void;
+use dll_proxy::winternals::{GetLastError, GetModuleHandleA};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::fmt::{Display, Formatter};
 use std::io::{Error, ErrorKind};
-use std::ops::{Deref, Index};
-use std::{fs, path};
-use std::path::Path;
-use std::time::Duration;
+use std::fs;
+use std::os::windows::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
 use dll_proxy::utils::MAX_PATH;
-use serde::Deserialize;
+use serde::{de, de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
 
+mod control_pipe;
+mod logging;
+#[cfg(feature = "Registry")]
+mod registry;
+mod winapi;
+
+use logging::{log, trace};
+use winapi::*;
+
+// The proxied dll is picked with a proxy_* feature, and is dinput8.dll when none of them are enabled. The proxy feature,
+// which is on by default, turns off the proxy and DllMain altogether, so the library can be tested on its own.
+// Only one proxy_* feature can be enabled. Counting them scales better than listing every pair that conflicts.
+const _: () = assert!(
+    cfg!(feature = "proxy_dinput8") as u8
+        + cfg!(feature = "proxy_winmm") as u8
+        + cfg!(feature = "proxy_version") as u8
+        + cfg!(feature = "proxy_dxgi") as u8
+        + cfg!(feature = "proxy_xinput1_3") as u8
+        + cfg!(feature = "proxy_xinput1_4") as u8
+        <= 1,
+    "Only one of the proxy_dinput8, proxy_winmm, proxy_version, proxy_dxgi, proxy_xinput1_3 and proxy_xinput1_4 \
+     features can be enabled."
+);
+
+#[cfg(all(
+    feature = "proxy",
+    any(
+        feature = "proxy_dinput8",
+        not(any(
+            feature = "proxy_winmm",
+            feature = "proxy_version",
+            feature = "proxy_dxgi",
+            feature = "proxy_xinput1_3",
+            feature = "proxy_xinput1_4"
+        ))
+    )
+))]
 proxy_dll!("dinput8.dll");
+#[cfg(feature = "proxy_winmm")]
+proxy_dll!("winmm.dll");
+#[cfg(feature = "proxy_version")]
+proxy_dll!("version.dll");
+#[cfg(feature = "proxy_dxgi")]
+proxy_dll!("dxgi.dll");
+#[cfg(feature = "proxy_xinput1_3")]
+proxy_dll!("xinput1_3.dll");
+#[cfg(feature = "proxy_xinput1_4")]
+proxy_dll!("xinput1_4.dll");
 
 const DLL_PROCESS_ATTACH: u32 = 1;
 const DLL_PROCESS_DETACH: u32 = 0;
-const PROCESS_ALL_ACCESS: u32 = 0x000F0000 | 0x00100000 | 0xFFFF;
-#[repr(C)]
-#[derive(Copy, Clone)]
-struct HANDLE(*const c_void);
-impl HANDLE {
-    pub fn is_valid(&self) -> bool {
-        self.0 != 0 as _ && self.0 != -1 as _
+
+#[derive(Debug)]
+pub enum AffinityError {
+    OpenProcessFailed(u32),
+    QueryFailed(u32),
+    SingleCpu,
+    EmptyProcessMask,
+    CpuZeroAlreadyOff,
+    NothingToExclude,
+    AlreadyNarrower { process: usize, requested: usize },
+    NoCoresLeft { include: usize, exclude: usize, system: usize },
+    NoAvailableCores { requested: usize, system: usize },
+    SetFailed { requested: usize, last_error: u32 },
+    ConstrainedByJob { requested: usize, job_affinity: Option<usize> },
+    NotApplied { requested: usize, applied: usize },
+    TooFewCores { remaining: u32, min_cores: u32 },
+    InvalidDelay(String),
+    InvalidConfig(String),
+}
+
+impl Display for AffinityError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AffinityError::OpenProcessFailed(last_error) => {
+                write!(f, "Unable to open process. Last Error: {}", format_last_error(*last_error))
+            }
+            AffinityError::QueryFailed(last_error) => {
+                write!(f, "Unable to get process affinity mask. Last Error: {}", format_last_error(*last_error))
+            }
+            AffinityError::SingleCpu => write!(f, "Only one CPU detected. Cannot change affinity."),
+            AffinityError::EmptyProcessMask => write!(
+                f,
+                "Windows reported an empty process affinity mask, which usually means a job object or container \
+                 limits the process, not the config. Leaving affinity unchanged."
+            ),
+            AffinityError::CpuZeroAlreadyOff => write!(f, "CPU 0 is already off. Leaving affinity unchanged."),
+            AffinityError::NothingToExclude => write!(f, "No cores to exclude, leaving affinity unchanged."),
+            AffinityError::AlreadyNarrower { process, requested } => write!(
+                f,
+                "Process already has a narrower affinity, leaving unchanged. Process: {} Config: {}",
+                describe_mask(*process),
+                describe_mask(*requested)
+            ),
+            AffinityError::NoCoresLeft { include, exclude, system } => write!(
+                f,
+                "Include and exclude lists leave no cores to run on. Include: {} Exclude: {} System: {}",
+                describe_mask(*include),
+                describe_mask(*exclude),
+                describe_mask(*system)
+            ),
+            AffinityError::NoAvailableCores { requested, system } => write!(
+                f,
+                "None of the requested cores are in the system affinity mask. Requested: {} System: {}",
+                describe_mask(*requested),
+                describe_mask(*system)
+            ),
+            AffinityError::SetFailed { requested, last_error } => write!(
+                f,
+                "Unable to set process affinity mask to {}. Last Error: {}",
+                describe_mask(*requested),
+                format_last_error(*last_error)
+            ),
+            AffinityError::ConstrainedByJob { requested, job_affinity: Some(job_affinity) } => write!(
+                f,
+                "Unable to set process affinity mask to {}. The process is in a job object that limits its affinity, \
+                 so only whatever created the job, like a launcher or sandbox, can change it. Job: {}",
+                describe_mask(*requested),
+                describe_mask(*job_affinity)
+            ),
+            // The limits of another process's job can't be read, so it might not be the job that blocked it.
+            AffinityError::ConstrainedByJob { requested, job_affinity: None } => write!(
+                f,
+                "Unable to set process affinity mask to {}. The process is in a job object, which can limit its \
+                 affinity so that only whatever created the job, like a launcher or sandbox, can change it.",
+                describe_mask(*requested)
+            ),
+            AffinityError::NotApplied { requested, applied } => write!(
+                f,
+                "Affinity mask was not applied as requested. Requested: {} Applied: {}",
+                describe_mask(*requested),
+                describe_mask(*applied)
+            ),
+            AffinityError::TooFewCores { remaining, min_cores } => write!(
+                f,
+                "The new affinity would leave {remaining} cores, but min_cores is {min_cores}. Leaving affinity unchanged."
+            ),
+            AffinityError::InvalidDelay(reason) => write!(f, "Invalid delay. {reason}"),
+            AffinityError::InvalidConfig(reason) => write!(f, "Invalid config. {reason}"),
+        }
     }
 }
 
-struct OwnedHandle(HANDLE);
+impl std::error::Error for AffinityError {}
+
 
-impl OwnedHandle {
-    pub unsafe fn new(handle: HANDLE) -> OwnedHandle {
-        Self(handle)
+impl From<AffinityError> for Error {
+    fn from(e: AffinityError) -> Self {
+        let kind = match e {
+            AffinityError::NoCoresLeft { .. }
+            | AffinityError::NoAvailableCores { .. }
+            | AffinityError::TooFewCores { .. } => ErrorKind::InvalidInput,
+            AffinityError::InvalidDelay(_) | AffinityError::InvalidConfig(_) => ErrorKind::InvalidData,
+            AffinityError::NotApplied { .. } => ErrorKind::Other,
+            _ => ErrorKind::PermissionDenied,
+        };
+        Error::new(kind, e)
     }
 }
 
-impl Deref for OwnedHandle {
-    type Target = HANDLE;
+// Every option that isn't in the config file keeps its value from Config::default().
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    #[serde(deserialize_with = "deserialize_duration", serialize_with = "serialize_duration")]
+    pub delay: Duration,
+    #[serde(deserialize_with = "deserialize_exclude")]
+    pub exclude: Vec<u32>,
+    #[serde(deserialize_with = "deserialize_optional_cores")]
+    pub include: Option<Vec<u32>>,
+    pub priority: Option<String>,
+    pub per_thread: bool,
+    pub exclude_ecores: bool,
+    pub reapply_interval: Option<f64>,
+    pub watch_config: bool,
+    pub log_file: Option<PathBuf>,
+    pub strict: bool,
+    pub control_pipe: Option<String>,
+    pub ideal_processor: Option<u32>,
+    pub exclude_percent: Option<f64>,
+    pub only_if_cpu0_enabled: bool,
+    pub power_throttling: Option<bool>,
+    pub delay_jitter: Option<f64>,
+    pub dry_run: bool,
+    pub follow_children: bool,
+    pub follow_children_window: Option<f64>,
+    pub lock_affinity: bool,
+    pub min_cores: Option<u32>,
+    pub numa_node: Option<u32>,
+    pub invert: bool,
+    pub retries: Option<u32>,
+    pub use_cpu_sets: bool,
+    pub wait_for_idle: bool,
+    pub wait_for_idle_timeout: Option<f64>,
+    pub mask: Option<String>,
+    pub thread_name_filter: Option<String>,
+    pub preset: Option<String>,
+    pub thread_priority: Option<String>,
+    pub release_after: Option<f64>,
+    pub enabled: bool,
+    pub one_thread_per_core: bool,
+    pub expected_exe: Option<String>,
+    pub min_reapply_gap: Option<f64>,
+    pub on_apply_command: Option<String>,
+    pub stages: Vec<Stage>,
+    pub exclude_packages: Vec<u32>,
+    pub trace: bool,
+    pub config_base: ConfigBase,
+    pub auto_exclude_busy: Option<u32>,
+    pub group: Option<u16>,
+    pub event_log: bool,
+    pub wait_for_window: Option<String>,
+    pub wait_for_window_timeout: Option<f64>,
+    pub balance_exclude: Option<u32>,
+    pub sibling_exes: Vec<String>,
+    pub description: Option<String>,
+    pub selftest: bool,
+    pub delay_until_idle: Option<f64>,
+    pub delay_until_idle_timeout: Option<f64>,
+    pub pin_to: Option<u32>,
+    pub respect_existing: bool,
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+// Used when there is no config file: exclude core 0 after 10 seconds, like the affinity.toml that comes with the dll.
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            delay: Duration::from_secs(10),
+            exclude: vec![0],
+            include: None,
+            priority: None,
+            per_thread: false,
+            exclude_ecores: false,
+            reapply_interval: None,
+            watch_config: false,
+            log_file: None,
+            strict: false,
+            control_pipe: None,
+            ideal_processor: None,
+            exclude_percent: None,
+            only_if_cpu0_enabled: true,
+            power_throttling: None,
+            delay_jitter: None,
+            dry_run: false,
+            follow_children: false,
+            follow_children_window: None,
+            lock_affinity: false,
+            min_cores: None,
+            numa_node: None,
+            invert: false,
+            retries: None,
+            use_cpu_sets: false,
+            wait_for_idle: false,
+            wait_for_idle_timeout: None,
+            mask: None,
+            thread_name_filter: None,
+            preset: None,
+            thread_priority: None,
+            release_after: None,
+            enabled: true,
+            one_thread_per_core: false,
+            expected_exe: None,
+            min_reapply_gap: None,
+            on_apply_command: None,
+            stages: vec![],
+            exclude_packages: vec![],
+            trace: false,
+            config_base: ConfigBase::Dll,
+            auto_exclude_busy: None,
+            group: None,
+            event_log: false,
+            wait_for_window: None,
+            wait_for_window_timeout: None,
+            balance_exclude: None,
+            sibling_exes: vec![],
+            description: None,
+            selftest: false,
+            delay_until_idle: None,
+            delay_until_idle_timeout: None,
+            pin_to: None,
+            respect_existing: false,
+        }
     }
 }
 
-impl Drop for OwnedHandle {
-    fn drop(&mut self) {
-        if self.is_valid() {
-            unsafe { CloseHandle(self.0) }
+/// The folder that relative paths in the config, like `log_file`, are relative to.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigBase {
+    /// The folder the config was read from, which is next to the dll unless `AFFINITY_CONFIG` is set.
+    Dll,
+    /// The folder of the executable the dll was loaded into.
+    Exe,
+    /// The working directory of the process when the path is used.
+    Cwd,
+}
+
+/// One step of [`Config::stages`]: `at` seconds after the dll is loaded, the affinity is set from this stage's cores
+/// instead of the ones in the config.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Stage {
+    pub at: f64,
+    #[serde(default, deserialize_with = "deserialize_exclude")]
+    pub exclude: Vec<u32>,
+    #[serde(default, deserialize_with = "deserialize_optional_cores")]
+    pub include: Option<Vec<u32>>,
+    #[serde(default)]
+    pub mask: Option<String>,
+}
+
+// A config file can either be a single flat config, or have a config per executable under [profiles.<exe_name>], with
+// an optional [default] config for any other executable.
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    profiles: HashMap<String, Config>,
+    default: Option<Config>,
+}
+
+// Picks the profile for the executable, ignoring case since Windows does, or the default profile if there isn't one.
+fn select_profile(mut config_file: ConfigFile, exe_name: &str) -> Option<Config> {
+    if let Some(config) = config_file.profiles.remove(exe_name) {
+        return Some(config);
+    }
+
+    let profile_name = config_file
+        .profiles
+        .keys()
+        .find(|name| name.eq_ignore_ascii_case(exe_name))
+        .cloned();
+    match profile_name {
+        Some(profile_name) => config_file.profiles.remove(&profile_name),
+        None => config_file.default,
+    }
+}
+
+// A single entry in a core list. Either a bare index like `8`, or a string spec like `"8"` or `"8-15"`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CoreSpec {
+    Index(u32),
+    Spec(String),
+}
+
+fn deserialize_cores<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u32>, D::Error> {
+    deserialize_core_list(deserializer, parse_core_spec)
+}
+
+fn deserialize_core_list<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    parse_spec: fn(&str) -> std::io::Result<Vec<u32>>,
+) -> Result<Vec<u32>, D::Error> {
+    let specs = Vec::<CoreSpec>::deserialize(deserializer)?;
+    let mut cores = vec![];
+    for spec in specs {
+        match spec {
+            CoreSpec::Index(i) => cores.push(i),
+            CoreSpec::Spec(s) => cores.extend(parse_spec(&s).map_err(de::Error::custom)?),
         }
     }
+
+    Ok(cores)
+}
+
+fn deserialize_exclude<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u32>, D::Error> {
+    deserialize_core_list(deserializer, parse_exclude_spec).map(|cores| normalize_exclude(&cores))
+}
+
+// The mask is the same either way, but a core listed twice is usually a copy-paste mistake, and a sorted list is
+// easier to read in the log, so exclude is always kept sorted and without duplicates.
+fn normalize_exclude(cores: &[u32]) -> Vec<u32> {
+    let (normalized, duplicates) = normalize_cores(cores);
+    if !duplicates.is_empty() {
+        log!("Cores {duplicates:?} are listed more than once in exclude {cores:?}. Ignoring the duplicates.");
+    }
+    normalized
+}
+
+// Returns the cores sorted and without duplicates, and every core that was listed more than once.
+fn normalize_cores(cores: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let mut normalized = cores.to_vec();
+    normalized.sort_unstable();
+    let mut duplicates: Vec<u32> =
+        normalized.windows(2).filter(|pair| pair[0] == pair[1]).map(|pair| pair[0]).collect();
+    duplicates.dedup();
+    normalized.dedup();
+    (normalized, duplicates)
 }
 
-#[link(name = "kernel32", kind = "raw-dylib")]
-extern "system" {
-    pub fn AllocConsole() -> u32;
-    pub fn AttachConsole(dwProcessId: u32) -> u32;
-    pub fn CloseHandle(object: HANDLE);
-    pub fn GetCurrentProcessId() -> u32;
-    pub fn OpenProcess(dwDesiredAccess: u32, bInheritHandle: bool, dwProcessId: u32) -> HANDLE;
-    pub fn GetProcessAffinityMask(
-        hProcess: HANDLE,
-        lpProcessAffinityMask: *mut usize,
-        lpSystemAffinityMask: *mut usize,
-    ) -> bool;
-    pub fn SetProcessAffinityMask(
-        hProcess: HANDLE,
-        lpProcessAffinityMask: usize,
-    ) -> bool;
+fn deserialize_optional_cores<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u32>>, D::Error> {
+    deserialize_cores(deserializer).map(Some)
 }
 
+// A duration is either a bare number of seconds, or a string like "500ms", "2s" or "1m30s".
 #[derive(Deserialize)]
-pub struct Config {
-    pub delay: f64,
-    pub exclude: Vec<u64>,
+#[serde(untagged)]
+enum DurationSpec {
+    Seconds(f64),
+    Spec(String),
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    parse_delay(DurationSpec::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+// Written as a number of seconds, which deserialize_duration reads back as the same delay.
+fn serialize_duration<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+// Nobody waits a day for the affinity to change, so a delay that long is a typo, like a delay in milliseconds without
+// the unit.
+const MAX_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn parse_delay(delay: DurationSpec) -> Result<Duration, AffinityError> {
+    let duration = match delay {
+        DurationSpec::Seconds(seconds) => seconds_to_duration(seconds, &seconds.to_string()),
+        DurationSpec::Spec(s) => parse_duration(&s),
+    }
+    .map_err(|e| AffinityError::InvalidDelay(e.to_string()))?;
+
+    if duration > MAX_DELAY {
+        return Err(AffinityError::InvalidDelay(format!(
+            "{duration:?} is longer than the maximum of {MAX_DELAY:?}"
+        )));
+    }
+
+    Ok(duration)
+}
+
+fn seconds_to_duration(seconds: f64, duration: &str) -> std::io::Result<Duration> {
+    Duration::try_from_secs_f64(seconds).map_err(|e| {
+        Error::new(ErrorKind::InvalidData, format!("Invalid duration \"{duration}\": {e}"))
+    })
+}
+
+// Parses a duration made of numbers with a unit (ms, s, m or h) after each one, like "1m30s". A bare number like "3"
+// is in seconds, the same as a number in the toml.
+fn parse_duration(duration: &str) -> std::io::Result<Duration> {
+    let invalid = |reason: String| {
+        Error::new(ErrorKind::InvalidData, format!("Invalid duration \"{duration}\": {reason}"))
+    };
+
+    let text = duration.trim();
+    if text.is_empty() {
+        return Err(invalid("duration is empty".to_string()));
+    }
+    if text.starts_with('-') {
+        return Err(invalid("duration can't be negative".to_string()));
+    }
+    if let Ok(seconds) = text.parse::<f64>() {
+        return seconds_to_duration(seconds, duration);
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let number_length = rest.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(number_length);
+        if number.is_empty() {
+            return Err(invalid(format!("expected a number at \"{rest}\"")));
+        }
+
+        let tail = tail.trim_start();
+        let unit_length = tail.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_length);
+        let value = number
+            .parse::<f64>()
+            .map_err(|e| invalid(format!("\"{number}\" is not a number: {e}")))?;
+        let seconds = match unit {
+            "ms" => value / 1000.0,
+            "s" => value,
+            "m" => value * 60.0,
+            "h" => value * 60.0 * 60.0,
+            "" => return Err(invalid(format!("missing a unit after \"{number}\""))),
+            _ => return Err(invalid(format!("unknown unit \"{unit}\". Expected ms, s, m or h"))),
+        };
+
+        total += seconds_to_duration(seconds, duration)?;
+        rest = tail.trim_start();
+    }
+
+    Ok(total)
+}
+
+// A mask like "0xFF00" or "0b1111000011110000", the way Task Manager and Process Lasso show them.
+fn parse_mask(mask: &str) -> std::io::Result<usize> {
+    let mask = mask.trim();
+    let (digits, radix) = match mask.get(..2) {
+        Some("0x" | "0X") => (&mask[2..], 16),
+        Some("0b" | "0B") => (&mask[2..], 2),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid mask \"{mask}\". Masks start with 0x for hex or 0b for binary, like \"0xFF00\""),
+            ))
+        }
+    };
+
+    usize::from_str_radix(digits, radix)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid mask \"{mask}\": {e}")))
+}
+
+// Parses a core spec like "8" or an inclusive range like "8-15" into the core indices it covers.
+fn parse_core_spec(spec: &str) -> std::io::Result<Vec<u32>> {
+    parse_core_spec_with(spec, total_logical_processors())
+}
+
+// Excluded cores are removed from a mask of the current processor group, so in exclude, `last` is the last core of
+// that group rather than of the whole machine. Include can pick cores in other groups, so it uses parse_core_spec.
+fn parse_exclude_spec(spec: &str) -> std::io::Result<Vec<u32>> {
+    parse_core_spec_with(spec, current_group_processor_count())
+}
+
+// `last` is the last logical processor and `last-N` is N before it, so the same config reserves the top cores on any
+// system.
+fn parse_core_spec_with(spec: &str, processor_count: u32) -> std::io::Result<Vec<u32>> {
+    let invalid_index = |index: &str, e: &dyn Display| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid core index \"{index}\" in core spec \"{spec}\": {e}"),
+        )
+    };
+    let parse_index = |index: &str| {
+        let Some(offset) = index.trim().strip_prefix("last") else {
+            return index.trim().parse::<u32>().map_err(|e| invalid_index(index, &e));
+        };
+        let offset = match offset.trim().strip_prefix('-') {
+            Some(offset) => offset.trim().parse::<u32>().map_err(|e| invalid_index(index, &e))?,
+            None if offset.trim().is_empty() => 0,
+            None => return Err(invalid_index(index, &"expected last or last-N")),
+        };
+        processor_count.checked_sub(1).and_then(|last| last.checked_sub(offset)).ok_or_else(|| {
+            invalid_index(
+                index,
+                &format!("it is before core 0. This system has {processor_count} logical processors."),
+            )
+        })
+    };
+
+    // last-1 is one core, not a range.
+    let Some((start, end)) = spec.split_once('-').filter(|_| !spec.trim().starts_with("last")) else {
+        return Ok(vec![parse_index(spec)?]);
+    };
+
+    let start = parse_index(start)?;
+    let end = parse_index(end)?;
+    if start > end {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Core range \"{spec}\" is reversed. Did you mean \"{end}-{start}\"?"),
+        ));
+    }
+
+    Ok((start..=end).collect())
 }
 
+#[cfg(feature = "proxy")]
 #[no_mangle]
 #[allow(unused)]
 pub extern "stdcall" fn DllMain(hinstDLL: usize, dwReason: u32, lpReserved: *mut usize) -> i32 {
@@ -83,119 +588,4565 @@ pub extern "stdcall" fn DllMain(hinstDLL: usize, dwReason: u32, lpReserved: *mut
                 AllocConsole();
                 AttachConsole(u32::MAX);
             }
-            let path = match init_proxy(hinstDLL) {
-                Ok(p) => p,
-                Err(e) => panic!("Could not proxy dll: {e}"),
-            };
-
-            let config = read_config_file(hinstDLL).expect("Could not read config");
-
-
-            std::thread::spawn(move || {
-                std::thread::sleep(Duration::from_secs_f64(config.delay));
-                println!("Changing affinity");
-                set_processor_affinity(get_exclude_mask(config.exclude)).expect("Could not set processor affinity");
-            });
+            match run_startup(hinstDLL) {
+                Ok(()) => {}
+                // A config that can't be read is a mistake the user has to fix, so it still stops the game.
+                Err(e @ StartupError::Config(_)) => panic!("{e}"),
+                // Anything the game calls in the real dll fails without the proxy, but a panic here would only show up
+                // as the game failing to start, so print why and keep loading.
+                Err(e) => log!("{e}"),
+            }
             1
         },
-        DLL_PROCESS_DETACH => 1,
+        DLL_PROCESS_DETACH => {
+            SHUTTING_DOWN.store(true, Ordering::SeqCst);
+            if let Err(e) = restore_processor_affinity() {
+                log!("Could not restore processor affinity: {e}");
+            }
+            1
+        }
         _ => 0,
     }
 }
 
-fn get_exclude_mask(exclude: Vec<u64>) -> usize {
-    let mut mask = 0;
-    for p in exclude {
-        mask |= 1 << p
+// Why the dll couldn't start. Loading the real dll and setting the affinity are separate problems, so each is tagged
+// with where it came from, [proxy] or [affinity].
+#[derive(Debug)]
+enum StartupError {
+    // The real dll at path couldn't be loaded, so the game can't use it.
+    Proxy { path: String, message: String },
+    Config(std::io::Error),
+    // Anything else that stops the affinity from being set. The proxy still works.
+    Affinity(String),
+}
+
+impl Display for StartupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StartupError::Proxy { path, message } => {
+                write!(f, "[proxy] Could not proxy {path}. The game won't be able to use it. {message}")
+            }
+            StartupError::Config(e) => write!(f, "[affinity] Could not read config: {e}"),
+            StartupError::Affinity(message) => write!(f, "[affinity] {message}"),
+        }
     }
+}
 
-    mask
+// Loads the real dll, reads the config and starts the affinity thread. Returns Ok when there is nothing to do, like
+// when the config is disabled, since the proxy keeps working either way.
+#[cfg(feature = "proxy")]
+unsafe fn run_startup(hinstDLL: usize) -> Result<(), StartupError> {
+    logging::set_trace(trace_variable_set());
+    let proxy_path = init_proxy(hinstDLL).map_err(|e| StartupError::Proxy {
+        path: get_proxied_dll_path(hinstDLL),
+        message: e.to_string(),
+    })?;
+    log!("Proxying {proxy_path}");
+
+    let context = AffinityContext::new().map_err(|e| {
+        StartupError::Affinity(format!("Could not get process affinity. Leaving affinity unchanged. {e}"))
+    })?;
+    // Every line after this one says which process it came from, for when the dll is loaded by more than one.
+    logging::set_process(context.process_name(), context.process_id());
+
+    let config_path = get_config_path(hinstDLL)
+        .map_err(|e| StartupError::Affinity(format!("Could not find config path. Leaving affinity unchanged. {e}")))?;
+    log!("Using config {}", config_path.display());
+    // A missing file uses the built-in defaults, so this is a config without a profile for this executable.
+    // There is nothing to do then, but the proxy still has to keep working.
+    let (config, priority) = match load_config(&config_path) {
+        Ok(config) => config,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            #[cfg(feature = "Console")]
+            log!("Leaving affinity unchanged. {e}");
+            return Ok(());
+        }
+        Err(e) => return Err(StartupError::Config(e)),
+    };
+    open_config_log_file(&config_path, &config);
+    logging::set_trace(config.trace || trace_variable_set());
+    if cfg!(feature = "Console") || config.log_file.is_some() {
+        print_banner(&proxy_path, &config_path, &config);
+    }
+    if cfg!(any(feature = "proxy_xinput1_3", feature = "proxy_xinput1_4")) {
+        check_ordinal_exports(hinstDLL, &proxy_path);
+    }
+    // The proxy keeps working either way, so this is a way to rule the dll out without removing it.
+    if !config.enabled {
+        log!("Affinity optimization disabled via config");
+        return Ok(());
+    }
+    if is_wine() {
+        log!("Running under Wine. The affinity might have no effect, and isn't read back after it is set.");
+    }
+    // A dll copied into the wrong game folder shouldn't change the affinity of whatever happens to load it.
+    if let Some(expected_exe) = &config.expected_exe {
+        if !expected_exe.eq_ignore_ascii_case(context.process_name()) {
+            log!(
+                "Warning: loaded into {} instead of {expected_exe}. Leaving affinity unchanged.",
+                context.process_name()
+            );
+            return Ok(());
+        }
+    }
+    #[cfg(feature = "Console")]
+    print_topology();
+    if let Some(pipe_name) = &config.control_pipe {
+        control_pipe::spawn_control_pipe(pipe_name, context.clone());
+    }
+
+    // The thread owns everything it uses, and the context only holds the pseudo-handle of the process, so nothing
+    // it touches is freed when the dll is unloaded.
+    std::thread::spawn(move || run_affinity_thread(context, config_path, config, priority));
+    Ok(())
 }
 
-const CONFIG_PATH: &str = "affinity.toml";
+// xinput1_3 and xinput1_4 export these by ordinal only, without a name. Games use XInputGetStateEx for the guide
+// button, and xinput1_3 doesn't have 104 or 108, so only the ones the real dll has are checked.
+const XINPUT_ORDINAL_EXPORTS: [(u16, &str); 6] = [
+    (100, "XInputGetStateEx"),
+    (101, "XInputWaitForGuideButton"),
+    (102, "XInputCancelGuideButtonWait"),
+    (103, "XInputPowerOffController"),
+    (104, "XInputGetBaseBusInformation"),
+    (108, "XInputGetCapabilitiesEx"),
+];
 
-unsafe fn get_file_name(hinstDLL: usize) -> String {
-    let mut buffer = [0u8; MAX_PATH + 1];
-    let name_size = GetModuleFileNameA(hinstDLL, buffer.as_mut_ptr(), buffer.len() as u32) as usize;
-    let name = &buffer[..name_size];
-    let name_str = std::str::from_utf8(name).expect("Could not parse name from GetModuleFileNameA");
-    name_str.to_string()
+// A proxy that only forwards named exports leaves the ordinal ones out. The named exports still work, so this is only
+// a warning.
+fn check_ordinal_exports(module_handle: usize, proxy_path: &str) {
+    let path: Vec<u16> = proxy_path.encode_utf16().chain(std::iter::once(0)).collect();
+    let real_module = unsafe { GetModuleHandleW(path.as_ptr()) };
+    let missing = get_missing_ordinal_exports(
+        &XINPUT_ORDINAL_EXPORTS,
+        |ordinal| exports_ordinal(real_module, ordinal),
+        |ordinal| exports_ordinal(module_handle, ordinal),
+    );
+    if !missing.is_empty() {
+        log!(
+            "[proxy] Warning: {proxy_path} exports {} by ordinal, and this dll doesn't forward them. The controller \
+             should still work, but games that use them, like for the guide button, might not.",
+            missing.join(", ")
+        );
+    }
 }
 
-fn read_config_file(hinstDLL: usize) -> std::io::Result<Config> {
-    let name = unsafe { get_file_name(hinstDLL) };
-    let path = Path::new(&name);
-    let working_dir = path.parent().unwrap().to_str().unwrap();
-    let f = fs::read_to_string(format!("{working_dir}/{CONFIG_PATH}")).expect("Could not read string");
-    toml::from_str(&f)
-        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+// The exports the real dll has that the proxy doesn't, as "name (ordinal)".
+fn get_missing_ordinal_exports(
+    ordinals: &[(u16, &str)],
+    real_exports: impl Fn(u16) -> bool,
+    proxy_exports: impl Fn(u16) -> bool,
+) -> Vec<String> {
+    ordinals
+        .iter()
+        .filter(|&&(ordinal, _)| real_exports(ordinal) && !proxy_exports(ordinal))
+        .map(|(ordinal, name)| format!("{name} ({ordinal})"))
+        .collect()
 }
 
-fn set_processor_affinity(exclude: usize) -> std::io::Result<()> {
-    let pid = unsafe { GetCurrentProcessId() };
+// GetProcAddress takes an ordinal in place of the name, in the low word of the pointer.
+fn exports_ordinal(module_handle: usize, ordinal: u16) -> bool {
+    let name = ordinal as usize as *const std::ffi::c_char;
+    module_handle != 0 && !unsafe { GetProcAddress(module_handle, name) }.is_null()
+}
 
-    let process_handle = unsafe { OpenProcess(PROCESS_ALL_ACCESS, true, pid) };
-    let process_handle = unsafe { OwnedHandle::new(process_handle) };
-    if !process_handle.is_valid() {
-        return Err(Error::new(
-            ErrorKind::PermissionDenied,
-            format!("Unable to open process. Last Error: {:X}", unsafe {
-                GetLastError()
-            }),
-        ));
+// The real dll has the same name as this one, and is loaded from the system folder. This is only for the error
+// message, so it falls back to whatever part of the path it could find.
+fn get_proxied_dll_path(hinstDLL: usize) -> String {
+    let name = match unsafe { get_file_name(hinstDLL) } {
+        Ok(path) => path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+        Err(_) => "the real dll".to_string(),
+    };
+    let mut buffer = [0u16; MAX_PATH + 1];
+    let length = unsafe { GetSystemDirectoryW(buffer.as_mut_ptr(), buffer.len() as u32) } as usize;
+    if length == 0 || length >= buffer.len() {
+        return name;
     }
 
-    let mut process_affinity_mask = 0;
-    let mut system_affinity_mask = 0;
+    decode_module_path(&buffer[..length]).join(name).display().to_string()
+}
 
-    if !unsafe { GetProcessAffinityMask(*process_handle, &mut process_affinity_mask, &mut system_affinity_mask) } {
-        return Err(Error::new(
-            ErrorKind::PermissionDenied,
-            format!("Unable to get process affinity mask. Last Error: {:X}", unsafe {
-                GetLastError()
-            }),
-        ));
+// Printed as one block, so it can be copied into a bug report as is.
+fn print_banner(proxy_path: &str, config_path: &Path, config: &Config) {
+    // The description is only there to remind the user why the config is the way it is.
+    let description = config.description.as_ref().map(|description| format!("\nDescription: {description}"));
+    log!(
+        "==== {} {} ====\nProxying: {proxy_path}\nConfig: {}{}\nTrigger: {:?}\nExclude: {:?}\nExclude mask: {}\n====",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        config_path.display(),
+        description.unwrap_or_default(),
+        config.trigger(),
+        config.exclude,
+        describe_mask(get_exclude_mask(&config.exclude))
+    );
+}
+
+fn open_config_log_file(config_path: &Path, config: &Config) {
+    let Some(log_file) = &config.log_file else {
+        return;
+    };
+
+    let log_path = match get_config_base(config_path, config.config_base) {
+        Ok(base) => std::path::absolute(base.join(log_file)).unwrap_or_else(|_| base.join(log_file)),
+        Err(e) => {
+            log!("Could not find the {:?} folder for log_file: {e}", config.config_base);
+            return;
+        }
+    };
+    log!("Logging to {}", log_path.display());
+    match logging::open_log_file(&log_path) {
+        Ok(()) => log!("Loaded {}: {config:?}", config_path.display()),
+        Err(e) => log!("Could not open log file {}: {e}", log_path.display()),
     }
+}
 
-    // If CPU 0 is already off, then return okay.
-    if process_affinity_mask & 1 != 1 {
-        return Ok(());
+// How often the config file is checked for changes when watch_config is set.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+fn run_affinity_thread(context: AffinityContext, config_path: PathBuf, mut config: Config, mut priority: Option<u32>) {
+    // Stages are timed from when this thread starts, right after the dll is loaded.
+    let attached = Instant::now();
+    if config.selftest {
+        log!("{}", format_benchmark(&benchmark_cores()));
+    }
+    if config.stages.is_empty() {
+        wait_for_trigger(config.trigger());
+        std::thread::sleep(get_delay_jitter(config.delay_jitter));
+    } else {
+        run_stages(&context, &config, priority, attached);
+        config = config.settled();
+    }
+    if shutting_down() {
+        return;
+    }
+    let mut exclude = get_config_exclude(&context, &config);
+    let mut expected_mask = apply_config(&context, &config, &exclude, priority).mask();
+    if config.follow_children && !config.dry_run {
+        match expected_mask.map(Ok).unwrap_or_else(|| get_process_affinity_mask(&context)) {
+            Ok(mask) => {
+                let window = config.follow_children_window;
+                std::thread::spawn(move || follow_children(mask, window));
+            }
+            Err(e) => log!("Could not get affinity mask for child processes: {e}"),
+        }
+    }
+    // The mask each sibling was last given, so only new siblings, or a new mask, are set on later ticks.
+    let mut siblings = HashMap::new();
+    update_siblings(&context, &config, expected_mask, &mut siblings);
+    if let Some(release_after) = config.release_after.filter(|_| !config.dry_run) {
+        release_affinity(Duration::from_secs_f64(release_after));
+        return;
     }
 
-    let clear_mask = !exclude;
-    let new_mask = process_affinity_mask & clear_mask;
-    if new_mask == 0 {
-        return Err(Error::new(
-            ErrorKind::PermissionDenied,
-            format!("Only one CPU detected. Cannot change affinity. Last Error: {:X}", unsafe {
-                GetLastError()
-            }),
-        ));
+    let mut last_modified = get_modified_time(&config_path);
+    let mut last_reapply = Instant::now();
+    // When the affinity was last set, for min_reapply_gap.
+    let mut last_apply = Instant::now();
+    loop {
+        let poll_interval = match (config.watch_config, config.reapply_interval) {
+            (true, _) => CONFIG_POLL_INTERVAL,
+            (false, Some(reapply_interval)) => Duration::from_secs_f64(reapply_interval),
+            (false, None) => return,
+        };
+        std::thread::sleep(poll_interval);
+        if shutting_down() {
+            return;
+        }
+        // The control pipe changed the affinity on purpose, so it isn't undone as if something else had changed it.
+        if let Some(pipe_mask) = take_control_pipe_mask() {
+            expected_mask = Some(pipe_mask);
+            last_apply = Instant::now();
+        }
+
+        if config.watch_config {
+            let modified = get_modified_time(&config_path);
+            if modified != last_modified {
+                last_modified = modified;
+                // A bad edit shouldn't take down the process, so keep using the last config that worked.
+                match reload_config(&config_path) {
+                    Ok((new_config, _)) if !new_config.enabled => {
+                        log!("Affinity optimization disabled via config. Restoring the original affinity.");
+                        if let Err(e) = restore_processor_affinity() {
+                            log!("Could not restore processor affinity: {e}");
+                        }
+                        return;
+                    }
+                    Ok((new_config, new_priority)) => {
+                        log!("Reloaded {}", config_path.display());
+                        open_config_log_file(&config_path, &new_config);
+                        logging::set_trace(new_config.trace || trace_variable_set());
+                        config = new_config.settled();
+                        priority = new_priority;
+                        exclude = get_config_exclude(&context, &config);
+                        // Saving the config is asking for it right away, so min_reapply_gap doesn't hold this back.
+                        // It only keeps reapplies from fighting the game, and the gap starts again from here.
+                        expected_mask = apply_config(&context, &config, &exclude, priority).mask();
+                        update_siblings(&context, &config, expected_mask, &mut siblings);
+                        last_reapply = Instant::now();
+                        last_apply = Instant::now();
+                        continue;
+                    }
+                    Err(e) => log!("Could not reload {}. Keeping the previous config. {e}", config_path.display()),
+                }
+            }
+        }
+
+        let Some(reapply_interval) = config.reapply_interval else {
+            continue;
+        };
+        if last_reapply.elapsed() < Duration::from_secs_f64(reapply_interval) {
+            continue;
+        }
+        last_reapply = Instant::now();
+
+        // Siblings can start after the game, so they are looked for on every tick, even if the affinity is unchanged.
+        let current_mask = get_process_affinity_mask(&context).ok();
+        update_siblings(&context, &config, expected_mask.or(current_mask), &mut siblings);
+        // Only reapply if something else changed the affinity since we last looked at it.
+        if current_mask.is_some() && current_mask == expected_mask {
+            continue;
+        }
+        // A game that manages its own affinity changes it right back, so don't fight it more often than this.
+        let min_reapply_gap = config.min_reapply_gap.map(Duration::from_secs_f64).unwrap_or_default();
+        if last_apply.elapsed() < min_reapply_gap {
+            log!(
+                "Affinity was changed, but it was set {:?} ago, less than min_reapply_gap. Skipping this reapply.",
+                last_apply.elapsed()
+            );
+            continue;
+        }
+        expected_mask = apply_affinity(&context, &config, &exclude).mask().or(current_mask);
+        last_apply = Instant::now();
     }
+}
 
-    if !unsafe { SetProcessAffinityMask(*process_handle, new_mask) } {
-        return Err(Error::new(
-            ErrorKind::PermissionDenied,
-            format!("Unable to set process affinity mask. Last Error: {:X}", unsafe {
-                GetLastError()
-            }),
-        ));
+// Gives the processes named in sibling_exes, like the audio or overlay helpers of a game, the same mask as this
+// process. `siblings` is the mask each one was last given, so a sibling is only set again when the mask changes.
+fn update_siblings(
+    context: &AffinityContext,
+    config: &Config,
+    mask: Option<usize>,
+    siblings: &mut HashMap<u32, usize>,
+) {
+    if config.sibling_exes.is_empty() || config.dry_run {
+        return;
     }
+    let mask = match mask.map(Ok).unwrap_or_else(|| get_process_affinity_mask(context)) {
+        Ok(mask) => mask,
+        Err(e) => {
+            log!("Could not get affinity mask for sibling processes: {e}");
+            return;
+        }
+    };
 
-    Ok(())
+    for name in &config.sibling_exes {
+        let pids = find_pids_by_name(name);
+        for pid in pids.into_iter().filter(|&pid| pid != context.process_id()) {
+            if siblings.get(&pid) == Some(&mask) {
+                continue;
+            }
+            match set_affinity_for_pid(pid, mask) {
+                Ok(()) => log!("Set affinity of sibling {name} {pid} to {}", describe_mask(mask)),
+                Err(e) => log!("Could not set affinity of sibling {name} {pid}: {e}"),
+            }
+            // Also after an error, so a process that can't be changed, like one running as admin, isn't tried on
+            // every tick.
+            siblings.insert(pid, mask);
+        }
+    }
 }
 
+// Restores the original affinity once `release_after` is over. There is nothing left to reapply after that, so the
+// config is no longer watched either.
+fn release_affinity(release_after: Duration) {
+    log!("Releasing affinity in {release_after:?}");
+    std::thread::sleep(release_after);
+    if shutting_down() {
+        return;
+    }
 
-#[test]
-fn test_toml() {
-    let toml = fs::read_to_string(CONFIG_PATH).unwrap();
-    let hinstDLL = unsafe { GetModuleHandleA(0 as _) };
-    let name = unsafe { get_file_name(hinstDLL) };
-    let path = Path::new(&name);
-    let working_dir = path.parent().unwrap().to_str().unwrap();
-    fs::write(format!("{working_dir}/{CONFIG_PATH}"), toml).unwrap();
+    match restore_processor_affinity() {
+        Ok(()) => log!("Released affinity, and restored the original affinity"),
+        Err(e) => log!("Could not release affinity: {e}"),
+    }
+}
+
+fn wait_for_trigger(trigger: Trigger) {
+    match trigger {
+        Trigger::Delay(delay) => std::thread::sleep(delay),
+        Trigger::InputIdle { timeout } => {
+            log!("Waiting for the process to be idle");
+            // Fails straight away for processes without a message queue, like console programs, which is logged and
+            // then treated the same as being idle.
+            match unsafe { WaitForInputIdle(self_process_handle(), get_wait_milliseconds(timeout)) } {
+                0 => log!("Process is idle"),
+                WAIT_TIMEOUT => log!("Timed out waiting for the process to be idle"),
+                _ => log!(
+                    "Unable to wait for the process to be idle. Last Error: {}",
+                    format_last_error(unsafe { GetLastError() })
+                ),
+            }
+        }
+        Trigger::CpuIdle { threshold, timeout } => {
+            log!("Waiting for the CPU usage to stay below {threshold}%, for at most {timeout:?}");
+            match wait_for_cpu_idle(threshold, timeout) {
+                Ok(true) => log!("CPU usage is below {threshold}%"),
+                Ok(false) => log!("Timed out waiting for the CPU usage to drop below {threshold}%"),
+                Err(e) => log!("Could not measure the CPU usage. Setting the affinity now. {e}"),
+            }
+        }
+        Trigger::Window { name, timeout } => {
+            log!("Waiting for a window matching \"{name}\"");
+            if wait_for_main_window(&name, timeout) {
+                log!("Found a window matching \"{name}\"");
+            } else {
+                log!("Timed out waiting for a window matching \"{name}\"");
+            }
+        }
+    }
+}
+
+// delay_until_idle samples the CPU usage for a second at a time, and waits for this many samples in a row below the
+// threshold, so a short pause between loading screens doesn't count.
+const CPU_IDLE_SAMPLE_MILLISECONDS: u32 = 1000;
+const CPU_IDLE_SAMPLES: u32 = 3;
+// The longest delay_until_idle waits when there is no delay_until_idle_timeout, since a game that never drops below the
+// threshold would never get its affinity set otherwise.
+const DEFAULT_CPU_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Returns false if the timeout ran out, or the dll is being unloaded, before the usage stayed below the threshold.
+fn wait_for_cpu_idle(threshold: f64, timeout: Duration) -> std::io::Result<bool> {
+    let started = Instant::now();
+    let mut calm_samples = 0;
+    while started.elapsed() < timeout && !SHUTTING_DOWN.load(Ordering::SeqCst) {
+        let usage = get_cpu_usage(&measure_core_load(CPU_IDLE_SAMPLE_MILLISECONDS)?);
+        trace!("CPU usage {usage:.1}%");
+        calm_samples = if usage < threshold { calm_samples + 1 } else { 0 };
+        if calm_samples >= CPU_IDLE_SAMPLES {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// The usage of the whole CPU as a percentage, which is the average of every core.
+fn get_cpu_usage(loads: &[f64]) -> f64 {
+    if loads.is_empty() {
+        return 0.0;
+    }
+
+    loads.iter().sum::<f64>() / loads.len() as f64 * 100.0
+}
+
+// How often the windows are checked for wait_for_window.
+const WINDOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Returns false if the timeout ran out, or the dll is being unloaded, before a matching window showed up.
+fn wait_for_main_window(name: &str, timeout: Option<Duration>) -> bool {
+    // A timeout too long to add to the current time is as good as none.
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    loop {
+        if find_process_window(name) {
+            return true;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) || SHUTTING_DOWN.load(Ordering::SeqCst) {
+            return false;
+        }
+        std::thread::sleep(WINDOW_POLL_INTERVAL);
+    }
+}
+
+struct WindowSearch<'a> {
+    process_id: u32,
+    name: &'a str,
+    found: bool,
+}
+
+// Only looks at the top level windows, which is where the main window of a game is.
+fn find_process_window(name: &str) -> bool {
+    let mut search = WindowSearch {
+        process_id: unsafe { GetCurrentProcessId() },
+        name,
+        found: false,
+    };
+    unsafe { EnumWindows(find_process_window_callback, &mut search as *mut WindowSearch as LPARAM) };
+    search.found
+}
+
+unsafe extern "system" fn find_process_window_callback(hwnd: HWND, lparam: LPARAM) -> i32 {
+    let search = &mut *(lparam as *mut WindowSearch);
+    let mut process_id = 0;
+    GetWindowThreadProcessId(hwnd, &mut process_id);
+    if process_id != search.process_id {
+        return 1;
+    }
+
+    let mut buffer = [0u16; 256];
+    let length = GetWindowTextW(hwnd, buffer.as_mut_ptr(), buffer.len() as i32);
+    let title = String::from_utf16_lossy(&buffer[..length.max(0) as usize]);
+    let length = GetClassNameW(hwnd, buffer.as_mut_ptr(), buffer.len() as i32);
+    let class = String::from_utf16_lossy(&buffer[..length.max(0) as usize]);
+    search.found = window_matches(search.name, &title, &class);
+    // Returning 0 stops the enumeration.
+    !search.found as i32
+}
+
+// The name can be part of the window title, or the whole class name. Both ignore case.
+fn window_matches(name: &str, title: &str, class: &str) -> bool {
+    let name = name.to_lowercase();
+    title.to_lowercase().contains(&name) || class.to_lowercase() == name
+}
+
+// INFINITE is u32::MAX milliseconds, so a long timeout stops just short of waiting forever.
+fn get_wait_milliseconds(timeout: Option<Duration>) -> u32 {
+    timeout.map_or(INFINITE, |timeout| timeout.as_millis().min(INFINITE as u128 - 1) as u32)
+}
+
+// RandomState is seeded randomly for each process, so it is enough to spread out processes that start at the same
+// time, without pulling in a random number crate.
+fn get_delay_jitter(delay_jitter: Option<f64>) -> Duration {
+    let Some(delay_jitter) = delay_jitter else {
+        return Duration::ZERO;
+    };
+
+    let random = RandomState::new().build_hasher().finish();
+    let jitter = scale_jitter(delay_jitter, random);
+    log!("Adding {jitter:?} of jitter to the delay");
+    jitter
+}
+
+// Scales `random` to a duration in [0, delay_jitter). This is done in whole nanoseconds, since rounding an f64 could
+// land exactly on delay_jitter.
+fn scale_jitter(delay_jitter: f64, random: u64) -> Duration {
+    // Capped so the product fits in a u128. That is still over 500 years.
+    let max_nanos = Duration::from_secs_f64(delay_jitter).as_nanos().min(u64::MAX as u128);
+    Duration::from_nanos(((max_nanos * random as u128) >> 64) as u64)
+}
+
+const CHILD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Launchers often start the real game as a child process, so this gives every child the same mask as this process.
+// Children that start later are picked up until the window runs out, or only the ones running now without a window.
+fn follow_children(mask: usize, window: Option<f64>) {
+    let pid = unsafe { GetCurrentProcessId() };
+    let deadline = Instant::now().checked_add(window.map(Duration::from_secs_f64).unwrap_or_default());
+    let mut seen = HashSet::new();
+    loop {
+        match child_pids(pid) {
+            Ok(children) => {
+                for child in children.into_iter().filter(|&child| seen.insert(child)) {
+                    match set_affinity_for_pid(child, mask) {
+                        Ok(()) => log!("Set affinity of child process {child} to {}", describe_mask(mask)),
+                        Err(e) => log!("Could not set affinity of child process {child}: {e}"),
+                    }
+                }
+            }
+            Err(e) => log!("Could not find child processes: {e}"),
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return;
+        }
+        std::thread::sleep(CHILD_POLL_INTERVAL);
+        if shutting_down() {
+            return;
+        }
+    }
+}
+
+fn get_config_exclude(context: &AffinityContext, config: &Config) -> Vec<u32> {
+    let mut exclude = config.exclude.clone();
+    if config.exclude_ecores {
+        exclude.extend(enumerate_efficiency_cores());
+    }
+    exclude.extend(get_counted_exclude(config, &context.system_info()));
+    if let Some(preset) = &config.preset {
+        match get_processor_cores().and_then(|cores| resolve_preset(preset, &cores)) {
+            Ok(mask) => exclude.extend(mask_to_core_list(mask)),
+            Err(e) => log!("Could not resolve preset {preset}: {e}"),
+        }
+    }
+    if !config.exclude_packages.is_empty() {
+        match get_package_cores(&config.exclude_packages) {
+            Ok(cores) => exclude.extend(cores),
+            Err(e) => log!("Could not exclude packages {:?}: {e}", config.exclude_packages),
+        }
+    }
+    // Last, so the cores that are already excluded aren't counted among the busiest.
+    if let Some(count) = config.auto_exclude_busy {
+        match measure_core_load(BUSY_SAMPLE_MILLISECONDS) {
+            Ok(loads) => {
+                let busiest = get_busiest_cores(&loads, count, &exclude);
+                log!("Core load: {loads:.2?}. Excluding the busiest cores {busiest:?}");
+                exclude.extend(busiest);
+            }
+            Err(e) => log!("Could not measure core load. Not excluding busy cores. {e}"),
+        }
+    }
+
+    exclude
+}
+
+// Applies every stage but the last, and waits for the last one. The last stage is applied like a config without
+// stages, so it is the one that is reapplied and given to child processes.
+fn run_stages(context: &AffinityContext, config: &Config, priority: Option<u32>, attached: Instant) {
+    for (i, stage) in config.stages.iter().enumerate() {
+        let at = Duration::from_secs_f64(stage.at);
+        std::thread::sleep(at.saturating_sub(attached.elapsed()));
+        if shutting_down() {
+            return;
+        }
+        log!("Stage {} of {} at {at:?}", i + 1, config.stages.len());
+        if i + 1 < config.stages.len() {
+            let stage_config = config.with_stage(stage);
+            apply_config(context, &stage_config, &get_config_exclude(context, &stage_config), priority);
+        }
+    }
+}
+
+// Applies everything in the config, and returns what happened to the affinity, like apply_affinity.
+fn apply_config(context: &AffinityContext, config: &Config, exclude: &[u32], priority: Option<u32>) -> AffinityOutcome {
+    let outcome = apply_affinity(context, config, exclude);
+    if config.dry_run {
+        log!("Dry run, so the ideal processor, power throttling and priorities are left alone");
+        return outcome;
+    }
 
-    let config = read_config_file(hinstDLL).expect("Could not read config");
+    apply_ideal_processor(config.ideal_processor);
+    apply_power_throttling(config.power_throttling);
+    apply_priority(priority);
+    apply_thread_priority(config.thread_priority.as_deref(), config.thread_name_filter.as_deref());
+    outcome
+}
+
+fn apply_priority(priority: Option<u32>) {
+    if let Some(priority) = priority {
+        log!("Changing priority");
+        if let Err(e) = set_priority_class(priority) {
+            log!("Could not set priority class: {e}");
+        }
+    }
+}
+
+// With a thread name filter, only the threads that match it get the new priority.
+fn apply_thread_priority(thread_priority: Option<&str>, thread_name_filter: Option<&str>) {
+    let Some(thread_priority) = thread_priority else {
+        return;
+    };
+    let priority = match get_thread_priority(thread_priority) {
+        Ok(priority) => priority,
+        Err(e) => {
+            log!("Could not set thread priority: {e}");
+            return;
+        }
+    };
+
+    let set_priority = |thread_handle: HANDLE| unsafe { SetThreadPriority(thread_handle, priority) };
+    match thread_name_filter {
+        Some(filter) => match for_each_named_thread(filter, set_priority) {
+            Ok((matched, updated)) => log!(
+                "Set thread priority to {thread_priority} for {updated} of the {matched} threads named like \"{filter}\""
+            ),
+            Err(e) => log!("Could not set thread priority: {e}"),
+        },
+        None => match for_each_thread(set_priority) {
+            Ok(updated) => log!("Set thread priority to {thread_priority} for {updated} threads"),
+            Err(e) => log!("Could not set thread priority: {e}"),
+        },
+    }
+}
+
+fn apply_ideal_processor(ideal_processor: Option<u32>) {
+    let Some(ideal_processor) = ideal_processor else {
+        return;
+    };
+
+    match set_thread_ideal_processor(ideal_processor) {
+        Ok(updated) => log!("Set ideal processor to {ideal_processor} for {updated} threads"),
+        Err(e) => log!("Could not set ideal processor: {e}"),
+    }
+}
+
+// Power throttling is only a battery saving, so if Windows doesn't support it, the rest of the config still applies.
+fn apply_power_throttling(power_throttling: Option<bool>) {
+    let Some(enabled) = power_throttling else {
+        return;
+    };
+
+    match set_power_throttling(enabled) {
+        Ok(()) => log!("Set power throttling to {enabled}"),
+        Err(e) => log!("Could not set power throttling. Continuing without it. {e}"),
+    }
+}
+
+fn get_modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// What apply_affinity did. It is returned, instead of read back from get_last_affinity_result, since
+// apply_affinity_toml can run on another thread at the same time as the affinity thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AffinityOutcome {
+    // The process affinity mask that was set, or None if it was set per thread with processor groups.
+    Applied(Option<usize>),
+    // Left unchanged on purpose. In a dry run, this is the process affinity mask that would have been set.
+    Unchanged(Option<usize>),
+    Failed,
+}
+
+impl AffinityOutcome {
+    // The process affinity mask to expect afterwards, if there is one.
+    fn mask(self) -> Option<usize> {
+        match self {
+            AffinityOutcome::Applied(mask) | AffinityOutcome::Unchanged(mask) => mask,
+            AffinityOutcome::Failed => None,
+        }
+    }
+}
+
+fn apply_affinity(context: &AffinityContext, config: &Config, exclude: &[u32]) -> AffinityOutcome {
+    log!("Changing affinity");
+    if config.lock_affinity && !config.dry_run {
+        apply_affinity_lock();
+    }
+    if let Some(group) = config.group {
+        return apply_group_affinity(config, group, exclude);
+    }
+    let group_sizes = get_group_sizes();
+    match &config.include {
+        Some(include) if group_sizes.len() > 1 && config.mask.is_none() && config.pin_to.is_none() => {
+            if config.dry_run {
+                log!("Dry run: would set group affinity for include {include:?} and exclude {exclude:?}");
+                set_last_affinity_result(RESULT_UNCHANGED, 0);
+                return AffinityOutcome::Unchanged(None);
+            }
+            match set_group_affinity(&group_sizes, include, exclude) {
+                Ok(updated) => {
+                    log!("Changed group affinity for {updated} threads");
+                    set_last_affinity_applied(0);
+                    AffinityOutcome::Applied(None)
+                }
+                Err(e) => {
+                    log!("Could not set group affinity. Leaving affinity unchanged. {e}");
+                    set_last_affinity_result(RESULT_FAILED, e.raw_os_error().unwrap_or_default() as u64);
+                    AffinityOutcome::Failed
+                }
+            }
+        }
+        include => {
+            // A raw mask, or the single core from pin_to, is used as is, instead of include and exclude.
+            let (include, exclude) = match config.mask.as_deref().map(parse_mask) {
+                Some(Ok(mask)) => (Some(mask), 0),
+                Some(Err(e)) => {
+                    log!("Could not parse mask. Leaving affinity unchanged. {e}");
+                    set_last_affinity_result(RESULT_FAILED, 0);
+                    return AffinityOutcome::Failed;
+                }
+                None => match config.pin_to {
+                    Some(core) => (Some(get_pin_mask(core)), 0),
+                    None => (include.as_deref().map(get_include_mask), get_exclude_mask(exclude)),
+                },
+            };
+            // Keeping one logical processor per core works like include, and exclude still removes cores from it.
+            let include = if config.one_thread_per_core && config.mask.is_none() {
+                match get_smt_primary_mask() {
+                    Ok(primary_mask) => Some(include.unwrap_or(usize::MAX) & primary_mask),
+                    Err(e) => {
+                        log!("Could not find SMT siblings. Keeping them. {e}");
+                        include
+                    }
+                }
+            } else {
+                include
+            };
+            match set_processor_affinity_with(
+                get_affinity_api(context, config).as_ref(),
+                include,
+                exclude,
+                &AffinityOptions {
+                    only_if_cpu0_enabled: config.only_if_cpu0_enabled,
+                    min_cores: config.min_cores,
+                    invert: config.invert,
+                    retries: config.retries.unwrap_or_default(),
+                    verify: !is_wine(),
+                    respect_existing: config.respect_existing,
+                },
+            ) {
+                Ok(new_mask) if config.dry_run => {
+                    if config.per_thread {
+                        log!("Dry run: would set affinity to {} for every thread", describe_mask(new_mask));
+                    }
+                    set_last_affinity_result(RESULT_UNCHANGED, 0);
+                    AffinityOutcome::Unchanged(Some(new_mask))
+                }
+                Ok(new_mask) => {
+                    log!("Changed affinity to {}", describe_mask(new_mask));
+                    set_last_affinity_applied(new_mask);
+                    run_on_apply_command(config.on_apply_command.as_deref(), new_mask);
+                    if config.event_log {
+                        report_affinity_event(context, new_mask);
+                    }
+                    if config.per_thread {
+                        match set_thread_affinity(new_mask) {
+                            Ok(updated) => log!("Changed affinity for {updated} threads"),
+                            Err(e) => log!("Could not set thread affinity: {e}"),
+                        }
+                    }
+                    AffinityOutcome::Applied(Some(new_mask))
+                }
+                Err(
+                    e @ (AffinityError::CpuZeroAlreadyOff
+                    | AffinityError::NothingToExclude
+                    | AffinityError::AlreadyNarrower { .. }
+                    | AffinityError::TooFewCores { .. }),
+                ) => {
+                    log!("{e}");
+                    set_last_affinity_result(RESULT_UNCHANGED, 0);
+                    AffinityOutcome::Unchanged(None)
+                }
+                Err(e) => {
+                    log!("Could not set processor affinity. Leaving affinity unchanged. {e}");
+                    set_last_affinity_result(RESULT_FAILED, get_error_code(&e));
+                    AffinityOutcome::Failed
+                }
+            }
+        }
+    }
+}
+
+// The environment variable on_apply_command gets the mask that was set in, as hex.
+const ON_APPLY_MASK_VAR: &str = "AFFINITY_MASK";
+
+// The command is run through cmd and isn't waited on, so a slow or broken command can't hold up the affinity.
+fn run_on_apply_command(command: Option<&str>, mask: usize) {
+    let Some(command) = command else {
+        return;
+    };
+
+    match Command::new("cmd").arg("/C").raw_arg(command).env(ON_APPLY_MASK_VAR, format!("{mask:#x}")).spawn() {
+        Ok(child) => log!("Started on_apply_command \"{command}\" with process id {}", child.id()),
+        Err(e) => log!("Could not start on_apply_command \"{command}\": {e}"),
+    }
+}
+
+// The event source name shown in the Application log. It isn't registered with a message file, so Event Viewer says the
+// description can't be found, and then shows the message anyway.
+const EVENT_SOURCE: &str = "AffinityOptimizer";
+const EVENT_ID_AFFINITY_APPLIED: u32 = 1;
+
+// Writing to the event log is only a note for admins, so if it fails, like when the user isn't allowed to register the
+// source, it is printed and the affinity is kept.
+fn report_affinity_event(context: &AffinityContext, mask: usize) {
+    let source: Vec<u16> = EVENT_SOURCE.encode_utf16().chain(std::iter::once(0)).collect();
+    let event_log = unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+    if !event_log.is_valid() {
+        log!(
+            "Could not register the event source. Not writing to the event log. Last Error: {}",
+            format_last_error(unsafe { GetLastError() })
+        );
+        return;
+    }
+
+    let message = format!(
+        "Changed the affinity of {} (process id {}) to {}",
+        context.process_name(),
+        context.process_id(),
+        describe_mask(mask)
+    );
+    let wide_message: Vec<u16> = message.encode_utf16().chain(std::iter::once(0)).collect();
+    let strings = [wide_message.as_ptr()];
+    let null = std::ptr::null();
+    let event_type = EVENTLOG_INFORMATION_TYPE;
+    let succeeded =
+        unsafe { ReportEventW(event_log, event_type, 0, EVENT_ID_AFFINITY_APPLIED, null, 1, 0, strings.as_ptr(), null) };
+    if succeeded == 0 {
+        log!("Could not write to the event log. Last Error: {}", format_last_error(unsafe { GetLastError() }));
+    }
+    unsafe { DeregisterEventSource(event_log) };
+}
+
+// With a group, the cores in the config are numbered from the start of that group, and every thread is moved there.
+// The mask is only for that group, so the outcome has no process affinity mask.
+fn apply_group_affinity(config: &Config, group: u16, exclude: &[u32]) -> AffinityOutcome {
+    let include_mask = match config.mask.as_deref().map(parse_mask).transpose() {
+        Ok(Some(mask)) => Some(mask),
+        Ok(None) => config.include.as_deref().map(core_list_to_mask),
+        Err(e) => {
+            log!("Could not parse mask. Leaving affinity unchanged. {e}");
+            set_last_affinity_result(RESULT_FAILED, 0);
+            return AffinityOutcome::Failed;
+        }
+    };
+    // A raw mask is used as is, like without a group.
+    let exclude_mask = if config.mask.is_some() { 0 } else { core_list_to_mask(exclude) };
+    let group_size = get_group_sizes().get(group as usize).copied().unwrap_or_default();
+    let target = match get_group_target(group, group_size, include_mask, exclude_mask) {
+        Ok(target) => target,
+        Err(e) => {
+            log!("Could not set affinity for processor group {group}. Leaving affinity unchanged. {e}");
+            set_last_affinity_result(RESULT_FAILED, 0);
+            return AffinityOutcome::Failed;
+        }
+    };
+    if config.dry_run {
+        log!("Dry run: would set group {group} affinity to {}", describe_mask(target.Mask));
+        set_last_affinity_result(RESULT_UNCHANGED, 0);
+        return AffinityOutcome::Unchanged(None);
+    }
+
+    match for_each_thread(|thread_handle| unsafe {
+        SetThreadGroupAffinity(thread_handle, &target, std::ptr::null_mut())
+    }) {
+        Ok(updated) => {
+            log!("Changed group {group} affinity to {} for {updated} threads", describe_mask(target.Mask));
+            set_last_affinity_applied(target.Mask);
+            AffinityOutcome::Applied(None)
+        }
+        Err(e) => {
+            log!("Could not set group affinity. Leaving affinity unchanged. {e}");
+            set_last_affinity_result(RESULT_FAILED, e.raw_os_error().unwrap_or_default() as u64);
+            AffinityOutcome::Failed
+        }
+    }
+}
+
+// Without include, every core of the group is included.
+fn get_group_target(
+    group: u16,
+    group_size: u32,
+    include: Option<usize>,
+    exclude: usize,
+) -> std::io::Result<GROUP_AFFINITY> {
+    let group_mask = if group_size >= usize::BITS { usize::MAX } else { (1 << group_size) - 1 };
+    let mask = include.unwrap_or(usize::MAX) & group_mask & !exclude;
+    if mask == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Include and exclude lists leave no cores to run on in processor group {group}."),
+        ));
+    }
+
+    Ok(GROUP_AFFINITY { Mask: mask, Group: group, Reserved: [0; 3] })
+}
+
+// Windows treats turning off auto updates as a one-way latch for the process, so this is never undone, even when the
+// original affinity is restored.
+fn apply_affinity_lock() {
+    let process_handle = self_process_handle();
+    if !unsafe { SetProcessAffinityUpdateMode(process_handle, PROCESS_AFFINITY_DISABLE_AUTO_UPDATE) } {
+        log!(
+            "Unable to disable affinity auto updates. Last Error: {}",
+            format_last_error(unsafe { GetLastError() })
+        );
+        return;
+    }
+
+    let mut update_mode = 0;
+    if !unsafe { QueryProcessAffinityUpdateMode(process_handle, &mut update_mode) } {
+        log!(
+            "Unable to query affinity update mode. Last Error: {}",
+            format_last_error(unsafe { GetLastError() })
+        );
+        return;
+    }
+    let enabled = update_mode & PROCESS_AFFINITY_ENABLE_AUTO_UPDATE != 0;
+    log!("Affinity auto updates are {}", if enabled { "still enabled" } else { "disabled" });
+}
+
+/// Builds an affinity mask with only the bit for `core` set, which is what `pin_to` sets the affinity to. Cores past
+/// the size of the mask give an empty mask.
+pub fn get_pin_mask(core: u32) -> usize {
+    1usize.checked_shl(core).unwrap_or_default()
+}
+
+/// Builds an affinity mask with a bit set for each of the given cores, to pass as `exclude` to
+/// [`set_processor_affinity`].
+pub fn get_exclude_mask(exclude: &[u32]) -> usize {
+    core_list_to_mask(exclude)
+}
+
+/// Builds an affinity mask with a bit set for each of the given cores, to pass as `include` to
+/// [`set_processor_affinity`].
+pub fn get_include_mask(include: &[u32]) -> usize {
+    core_list_to_mask(include)
+}
+
+/// Builds an affinity mask with a bit set for each of the given cores. The inverse of [`mask_to_core_list`].
+///
+/// `SetProcessAffinityMask` can only address the cores in the current processor group, which is one bit per core in a
+/// usize, so any core index that doesn't fit is skipped.
+pub fn core_list_to_mask(cores: &[u32]) -> usize {
+    let mut mask = 0usize;
+    for &p in cores {
+        match 1usize.checked_shl(p) {
+            Some(bit) => mask |= bit,
+            None => log!("Core {p} is out of range for the affinity mask ({} cores max). Skipping.", usize::BITS),
+        }
+    }
+
+    mask
+}
+
+const CONFIG_PATH: &str = "affinity.toml";
+const JSON_CONFIG_PATH: &str = "affinity.json";
+
+// Paths can be longer than MAX_PATH, so the buffer is grown until the whole path fits, up to the longest path Windows
+// supports.
+const MAX_LONG_PATH: usize = 32767;
+
+unsafe fn get_file_name(hinstDLL: usize) -> std::io::Result<PathBuf> {
+    let mut buffer = vec![0u16; MAX_PATH + 1];
+    loop {
+        let name_size = GetModuleFileNameW(hinstDLL, buffer.as_mut_ptr(), buffer.len() as u32) as usize;
+        if name_size == 0 {
+            return Err(Error::other(format!(
+                "Could not get module file name. Last Error: {}",
+                format_last_error(GetLastError())
+            )));
+        }
+
+        // When the path doesn't fit, it is truncated to the size of the buffer, and the whole buffer is returned as the
+        // size. Newer versions of Windows also set ERROR_INSUFFICIENT_BUFFER, but XP doesn't, and drops the null
+        // terminator instead, so the size is the only check that works on both. A truncated path is never used.
+        if name_size < buffer.len() {
+            return Ok(decode_module_path(&buffer[..name_size]));
+        }
+
+        if buffer.len() > MAX_LONG_PATH {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Module file name is longer than {MAX_LONG_PATH} characters"),
+            ));
+        }
+        buffer.resize(buffer.len() * 2, 0);
+    }
+}
+
+fn decode_module_path(name: &[u16]) -> PathBuf {
+    PathBuf::from(String::from_utf16_lossy(name))
+}
+
+// Set to the path of a config file to use it instead of the one next to the dll.
+const CONFIG_PATH_VARIABLE: &str = "AFFINITY_CONFIG";
+// Set to anything but 0 to trace the Windows calls, like trace = true, before the config is even read.
+const TRACE_VARIABLE: &str = "AFFINITY_TRACE";
+
+fn trace_variable_set() -> bool {
+    std::env::var_os(TRACE_VARIABLE).is_some_and(|value| !value.is_empty() && value != "0")
+}
+
+// The result of a Windows call for the trace, with the last error when it failed.
+fn trace_result(succeeded: bool, last_error: u32) -> String {
+    if succeeded {
+        "true".to_string()
+    } else {
+        format!("false, Last Error: {}", format_last_error(last_error))
+    }
+}
+
+fn get_config_path(hinstDLL: usize) -> std::io::Result<PathBuf> {
+    if let Some(config_path) = get_config_path_override(std::env::var_os(CONFIG_PATH_VARIABLE)) {
+        return Ok(config_path);
+    }
+
+    let path = unsafe { get_file_name(hinstDLL)? };
+    let working_dir = path.parent().ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("Could not determine DLL directory from {}", path.display()),
+        )
+    })?;
+
+    // affinity.toml is used when both exist.
+    let config_path = working_dir.join(CONFIG_PATH);
+    let json_config_path = working_dir.join(JSON_CONFIG_PATH);
+    if !config_path.exists() && json_config_path.exists() {
+        return Ok(json_config_path);
+    }
+
+    Ok(config_path)
+}
+
+// A path that doesn't exist is ignored, so a stale variable doesn't stop the config next to the dll from loading.
+fn get_config_path_override(value: Option<std::ffi::OsString>) -> Option<PathBuf> {
+    let config_path = PathBuf::from(value?);
+    if !config_path.is_file() {
+        log!(
+            "{CONFIG_PATH_VARIABLE} is set to {}, which doesn't exist. Using the config next to the dll.",
+            config_path.display()
+        );
+        return None;
+    }
+
+    log!("Using {} from {CONFIG_PATH_VARIABLE}", config_path.display());
+    Some(config_path)
+}
+
+// Some games change their working directory after loading, so cwd is only used when the config asks for it.
+fn get_config_base(config_path: &Path, config_base: ConfigBase) -> std::io::Result<PathBuf> {
+    let base = match config_base {
+        ConfigBase::Dll => config_path.parent().map(Path::to_path_buf),
+        ConfigBase::Exe => unsafe { get_file_name(0)? }.parent().map(Path::to_path_buf),
+        ConfigBase::Cwd => Some(std::env::current_dir()?),
+    };
+    Ok(base.unwrap_or_default())
+}
+
+fn get_host_exe_name() -> std::io::Result<String> {
+    let path = unsafe { get_file_name(0)? };
+    let exe_name = path.file_name().ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("Could not determine executable name from {}", path.display()),
+        )
+    })?;
+    Ok(exe_name.to_string_lossy().into_owned())
+}
+
+fn read_config_file(config_path: &Path, system: &SystemInfo) -> std::io::Result<Config> {
+    // The registry key is only there on machines that are set up that way, and otherwise the file is used as usual.
+    #[cfg(feature = "Registry")]
+    if let Some(config) = registry::read_registry_config(&get_host_exe_name()?)? {
+        config.validate(system)?;
+        return Ok(config);
+    }
+
+    let config = match read_config_profile(config_path) {
+        Err(e) if e.kind() == ErrorKind::NotFound && !config_path.exists() => {
+            log!("Could not find {}. Using the built-in defaults.", config_path.display());
+            Config::default()
+        }
+        config => config?,
+    };
+    config.validate(system)?;
+    Ok(config)
+}
+
+fn read_config_profile(config_path: &Path) -> std::io::Result<Config> {
+    let f = fs::read_to_string(config_path)?;
+    let config_file: ConfigFile = parse_config(config_path, &f)?;
+    if config_file.profiles.is_empty() && config_file.default.is_none() {
+        return parse_config(config_path, &f);
+    }
+
+    let exe_name = get_host_exe_name()?;
+    select_profile(config_file, &exe_name).ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("No profile for {exe_name} in {}, and no [default] profile", config_path.display()),
+        )
+    })
+}
+
+// A .json config is parsed as json, and anything else as toml.
+fn parse_config<T: DeserializeOwned>(config_path: &Path, contents: &str) -> std::io::Result<T> {
+    let is_json = config_path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+    if is_json {
+        return serde_json::from_str(contents).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()));
+    }
+
+    toml::from_str(contents).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+}
+
+// Reads and validates the config, then resolves the parts of it that depend on the system.
+fn load_config(config_path: &Path) -> std::io::Result<(Config, Option<u32>)> {
+    let system = SystemInfo::query();
+    let config = read_config_file(config_path, &system)?;
+    resolve_config(config, &system)
+}
+
+// The built-in defaults are only for a game that starts without a config, so deleting the file while watch_config is
+// on, or an editor that saves by replacing it, keeps the config the game already has.
+fn reload_config(config_path: &Path) -> std::io::Result<(Config, Option<u32>)> {
+    if !config_path.exists() {
+        return Err(Error::new(ErrorKind::NotFound, format!("{} was removed", config_path.display())));
+    }
+
+    load_config(config_path)
+}
+
+// Resolves the parts of a validated config that can be invalid even when the toml is valid.
+fn resolve_config(mut config: Config, system: &SystemInfo) -> std::io::Result<(Config, Option<u32>)> {
+    let priority = config.priority.as_deref().map(get_priority_class).transpose()?;
+
+    // Strict mode already rejected missing cores in validate, so this only warns about them.
+    config.exclude = check_core_indices("exclude", &config.exclude, system.processor_count, false)?;
+    if let Some(include) = &config.include {
+        config.include = Some(check_core_indices("include", include, system.processor_count, false)?);
+    }
+    if let Some(group) = config.group {
+        check_group(group, unsafe { GetActiveProcessorGroupCount() })
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    }
+    // The node's cores become the include list, or narrow it down if there already is one.
+    if let Some(numa_node) = config.numa_node {
+        let node_cores = get_numa_node_cores(numa_node)?;
+        config.include = Some(match config.include.take() {
+            Some(include) => include.into_iter().filter(|core| node_cores.contains(core)).collect(),
+            None => node_cores,
+        });
+    }
+
+    Ok((config, priority))
+}
+
+/// The parts of the system that [`Config::validate`] checks a config against.
+#[derive(Debug, Clone)]
+pub struct SystemInfo {
+    /// The number of logical processors across every processor group.
+    pub processor_count: u32,
+    /// The number of logical processors in the current processor group.
+    pub group_processor_count: u32,
+    /// The logical processors in the current processor group, one bit per processor.
+    pub system_affinity_mask: usize,
+}
+
+impl SystemInfo {
+    pub fn query() -> Self {
+        let mut system_info: SYSTEM_INFO = unsafe { std::mem::zeroed() };
+        unsafe { GetSystemInfo(&mut system_info) };
+        SystemInfo {
+            processor_count: total_logical_processors(),
+            group_processor_count: system_info.dwNumberOfProcessors,
+            system_affinity_mask: system_info.dwActiveProcessorMask,
+        }
+    }
+}
+
+/// What the affinity thread waits for before it first sets the affinity.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trigger {
+    /// Waits for a fixed delay.
+    Delay(Duration),
+    /// Waits until the process has started its message loop and is waiting for input, or until `timeout` if it is set.
+    InputIdle { timeout: Option<Duration> },
+    /// Waits until the CPU usage of the whole system stays below `threshold` percent for a few seconds, or until
+    /// `timeout`.
+    CpuIdle { threshold: f64, timeout: Duration },
+    /// Waits until this process has a top level window with `name` in its title, or `name` as its class, or until
+    /// `timeout` if it is set.
+    Window { name: String, timeout: Option<Duration> },
+}
+
+impl Config {
+    /// The trigger the config asks for. `wait_for_idle`, `wait_for_window` or `delay_until_idle` takes the place of
+    /// `delay`.
+    pub fn trigger(&self) -> Trigger {
+        if let Some(threshold) = self.delay_until_idle {
+            return Trigger::CpuIdle {
+                threshold,
+                timeout: self.delay_until_idle_timeout.map_or(DEFAULT_CPU_IDLE_TIMEOUT, Duration::from_secs_f64),
+            };
+        }
+        if let Some(name) = &self.wait_for_window {
+            return Trigger::Window {
+                name: name.clone(),
+                timeout: self.wait_for_window_timeout.map(Duration::from_secs_f64),
+            };
+        }
+        if self.wait_for_idle {
+            return Trigger::InputIdle {
+                timeout: self.wait_for_idle_timeout.map(Duration::from_secs_f64),
+            };
+        }
+
+        Trigger::Delay(self.delay)
+    }
+
+    /// Checks everything in the config that can be checked before it is applied, and returns the first problem, so a
+    /// bad config fails with one clear message before the affinity is touched.
+    pub fn validate(&self, system: &SystemInfo) -> Result<(), AffinityError> {
+        if self.delay > MAX_DELAY {
+            return Err(AffinityError::InvalidDelay(format!(
+                "{:?} is longer than the maximum of {MAX_DELAY:?}",
+                self.delay
+            )));
+        }
+        if let Some(priority) = &self.priority {
+            get_priority_class(priority).map_err(|e| AffinityError::InvalidConfig(e.to_string()))?;
+        }
+        if let Some(thread_priority) = &self.thread_priority {
+            get_thread_priority(thread_priority).map_err(|e| AffinityError::InvalidConfig(e.to_string()))?;
+        }
+        if self.strict {
+            check_core_indices("exclude", &self.exclude, system.processor_count, true)
+                .map_err(|e| AffinityError::InvalidConfig(e.to_string()))?;
+            if let Some(include) = &self.include {
+                check_core_indices("include", include, system.processor_count, true)
+                    .map_err(|e| AffinityError::InvalidConfig(e.to_string()))?;
+            }
+        }
+        if self.invert {
+            check_invert(self)?;
+        }
+        // The topology doesn't matter for checking the name.
+        if let Some(preset) = &self.preset {
+            resolve_preset(preset, &[]).map_err(|e| AffinityError::InvalidConfig(e.to_string()))?;
+        }
+        if self.thread_name_filter.is_some() {
+            check_thread_name_filter(self)?;
+        }
+        if self.group.is_some() {
+            check_group_conflicts(self)?;
+        }
+        if self.pin_to.is_some() {
+            check_pin_to(self)?;
+        }
+        if let Some(ideal_processor) = self.ideal_processor {
+            // With invert, the cores that can't be used are the ones that aren't listed. Both the ideal processor and
+            // invert only work within the current processor group.
+            let unusable = if self.invert {
+                (0..system.group_processor_count).filter(|core| !self.exclude.contains(core)).collect()
+            } else {
+                self.exclude.clone()
+            };
+            check_ideal_processor(ideal_processor, &unusable, system.group_processor_count)?;
+        }
+        if let Some(exclude_percent) = self.exclude_percent {
+            if !(0.0..=100.0).contains(&exclude_percent) {
+                return Err(AffinityError::InvalidConfig(format!(
+                    "exclude_percent {exclude_percent} must be between 0 and 100"
+                )));
+            }
+        }
+        check_seconds("delay_jitter", self.delay_jitter)?;
+        check_seconds("follow_children_window", self.follow_children_window)?;
+        check_seconds("wait_for_idle_timeout", self.wait_for_idle_timeout)?;
+        check_seconds("wait_for_window_timeout", self.wait_for_window_timeout)?;
+        check_seconds("delay_until_idle_timeout", self.delay_until_idle_timeout)?;
+        if let Some(delay_until_idle) = self.delay_until_idle {
+            if !(0.0..=100.0).contains(&delay_until_idle) {
+                return Err(AffinityError::InvalidConfig(format!(
+                    "delay_until_idle {delay_until_idle} must be between 0 and 100"
+                )));
+            }
+        }
+        check_triggers(self)?;
+        check_seconds("release_after", self.release_after)?;
+        check_seconds("min_reapply_gap", self.min_reapply_gap)?;
+        check_seconds("reapply_interval", self.reapply_interval)?;
+        // An interval of 0 would check the affinity in a loop without ever sleeping.
+        if self.reapply_interval == Some(0.0) {
+            return Err(AffinityError::InvalidConfig("reapply_interval must be more than 0 seconds".to_string()));
+        }
+        check_stages(self, system)?;
+
+        check_affinity_left(self, system)
+    }
+
+    // The config with the cores of the stage in place of its own.
+    fn with_stage(&self, stage: &Stage) -> Config {
+        Config {
+            exclude: stage.exclude.clone(),
+            include: stage.include.clone(),
+            mask: stage.mask.clone(),
+            stages: vec![],
+            ..self.clone()
+        }
+    }
+
+    // Once every stage has run, the last one stays applied.
+    fn settled(self) -> Config {
+        match self.stages.last() {
+            Some(stage) => self.with_stage(stage),
+            None => self,
+        }
+    }
+}
+
+fn check_affinity_left(config: &Config, system: &SystemInfo) -> Result<(), AffinityError> {
+    // A raw mask replaces include and exclude, so it is the only thing left to check.
+    if let Some(mask) = &config.mask {
+        let mask = parse_mask(mask).map_err(|e| AffinityError::InvalidConfig(e.to_string()))?;
+        if mask & system.system_affinity_mask == 0 {
+            return Err(AffinityError::NoCoresLeft {
+                include: mask,
+                exclude: 0,
+                system: system.system_affinity_mask,
+            });
+        }
+        return Ok(());
+    }
+    if let Some(core) = config.pin_to {
+        let mask = get_pin_mask(core);
+        if mask & system.system_affinity_mask == 0 {
+            return Err(AffinityError::NoAvailableCores {
+                requested: mask,
+                system: system.system_affinity_mask,
+            });
+        }
+        return Ok(());
+    }
+
+    check_cores_left(config, system)
+}
+
+// Stages run in order, so each one has to be at or after the one before it, and each has to leave some cores.
+fn check_stages(config: &Config, system: &SystemInfo) -> Result<(), AffinityError> {
+    let mut previous = 0.0;
+    for stage in &config.stages {
+        check_seconds("stage at", Some(stage.at))?;
+        if stage.at < previous {
+            return Err(AffinityError::InvalidConfig(format!(
+                "The stage at {} comes before the stage at {previous} that is listed above it",
+                stage.at
+            )));
+        }
+        previous = stage.at;
+        check_affinity_left(&config.with_stage(stage), system)?;
+    }
+
+    Ok(())
+}
+
+// Include cores past the current processor group are set per thread with group affinity, so they can't be checked
+// against the system mask here.
+fn check_cores_left(config: &Config, system: &SystemInfo) -> Result<(), AffinityError> {
+    if config.include.iter().flatten().any(|&core| core >= system.group_processor_count) {
+        return Ok(());
+    }
+
+    let exclude = get_exclude_mask(&config.exclude);
+    let include = config.include.as_deref().map(get_include_mask).unwrap_or(system.system_affinity_mask);
+    let cores_left = if config.invert && config.include.is_none() {
+        include & exclude
+    } else {
+        include & system.system_affinity_mask & !exclude
+    };
+    if cores_left == 0 {
+        return Err(AffinityError::NoCoresLeft {
+            include,
+            exclude,
+            system: system.system_affinity_mask,
+        });
+    }
+
+    Ok(())
+}
+
+// The other ways of picking cores either add to exclude, which would keep those cores instead of removing them, or
+// replace it with include, so they can't be combined with invert.
+fn check_invert(config: &Config) -> Result<(), AffinityError> {
+    let conflicts = [
+        ("include", config.include.is_some()),
+        ("mask", config.mask.is_some()),
+        ("numa_node", config.numa_node.is_some()),
+        ("exclude_ecores", config.exclude_ecores),
+        ("exclude_percent", config.exclude_percent.is_some()),
+        ("balance_exclude", config.balance_exclude.is_some()),
+        ("preset", config.preset.is_some()),
+        ("one_thread_per_core", config.one_thread_per_core),
+        ("exclude_packages", !config.exclude_packages.is_empty()),
+        ("auto_exclude_busy", config.auto_exclude_busy.is_some()),
+    ];
+    match conflicts.iter().find(|(_, set)| *set) {
+        Some((name, _)) => Err(AffinityError::InvalidConfig(format!("invert can't be used with {name}"))),
+        None => Ok(()),
+    }
+}
+
+// These all pick cores by their number across every processor group, or only look at the group the process is in.
+fn check_group_conflicts(config: &Config) -> Result<(), AffinityError> {
+    let conflicts = [
+        ("invert", config.invert),
+        ("numa_node", config.numa_node.is_some()),
+        ("exclude_ecores", config.exclude_ecores),
+        ("exclude_packages", !config.exclude_packages.is_empty()),
+        ("preset", config.preset.is_some()),
+        ("one_thread_per_core", config.one_thread_per_core),
+        ("auto_exclude_busy", config.auto_exclude_busy.is_some()),
+        ("use_cpu_sets", config.use_cpu_sets),
+        ("thread_name_filter", config.thread_name_filter.is_some()),
+    ];
+    match conflicts.iter().find(|(_, set)| *set) {
+        Some((name, _)) => Err(AffinityError::InvalidConfig(format!("group can't be used with {name}"))),
+        None => Ok(()),
+    }
+}
+
+// pin_to replaces include and exclude like a raw mask does, so only the options that change how it is set conflict.
+fn check_pin_to(config: &Config) -> Result<(), AffinityError> {
+    let conflicts = [
+        ("mask", config.mask.is_some()),
+        ("invert", config.invert),
+        ("group", config.group.is_some()),
+        ("one_thread_per_core", config.one_thread_per_core),
+    ];
+    match conflicts.iter().find(|(_, set)| *set) {
+        Some((name, _)) => Err(AffinityError::InvalidConfig(format!("pin_to can't be used with {name}"))),
+        None => Ok(()),
+    }
+}
+
+fn check_group(group: u16, group_count: u16) -> Result<(), AffinityError> {
+    if group >= group_count {
+        return Err(AffinityError::InvalidConfig(format!(
+            "Processor group {group} doesn't exist. This system has processor groups 0-{}.",
+            group_count.saturating_sub(1)
+        )));
+    }
+
+    Ok(())
+}
+
+// per_thread would change every thread anyway, and CPU Sets are for the whole process.
+fn check_thread_name_filter(config: &Config) -> Result<(), AffinityError> {
+    let conflicts = [("per_thread", config.per_thread), ("use_cpu_sets", config.use_cpu_sets)];
+    match conflicts.iter().find(|(_, set)| *set) {
+        Some((name, _)) => Err(AffinityError::InvalidConfig(format!("thread_name_filter can't be used with {name}"))),
+        None => Ok(()),
+    }
+}
+
+// Every number of seconds is turned into a Duration later, so this rejects anything that wouldn't fit in one, along
+// with negative numbers and ones that aren't numbers.
+fn check_seconds(name: &str, seconds: Option<f64>) -> Result<(), AffinityError> {
+    match seconds.map(|seconds| (seconds, Duration::try_from_secs_f64(seconds))) {
+        Some((seconds, Err(e))) => Err(AffinityError::InvalidConfig(format!(
+            "{name} {seconds} must be a positive number of seconds. {e}"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// Only one thing can be waited for before the affinity is set.
+fn check_triggers(config: &Config) -> Result<(), AffinityError> {
+    let triggers = [
+        ("wait_for_idle", config.wait_for_idle),
+        ("wait_for_window", config.wait_for_window.is_some()),
+        ("delay_until_idle", config.delay_until_idle.is_some()),
+    ];
+    let mut set = triggers.iter().filter(|(_, set)| *set).map(|(name, _)| name);
+    match (set.next(), set.next()) {
+        (Some(first), Some(second)) => {
+            Err(AffinityError::InvalidConfig(format!("{first} can't be used with {second}")))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Unlike a typo in exclude, a bad ideal processor is always an error, since it is the only core the option names.
+fn check_ideal_processor(ideal_processor: u32, exclude: &[u32], processor_count: u32) -> Result<(), AffinityError> {
+    if ideal_processor >= processor_count {
+        return Err(AffinityError::InvalidConfig(format!(
+            "ideal_processor {ideal_processor} doesn't exist. This system has {processor_count} logical processors (0-{}).",
+            processor_count.saturating_sub(1)
+        )));
+    }
+    if exclude.contains(&ideal_processor) {
+        return Err(AffinityError::InvalidConfig(format!(
+            "ideal_processor {ideal_processor} is also in exclude"
+        )));
+    }
+
+    Ok(())
+}
+
+// exclude_percent and balance_exclude go into the exclude mask, which only covers the current processor group, so they
+// count the cores of that group, not the whole machine.
+fn get_counted_exclude(config: &Config, system: &SystemInfo) -> Vec<u32> {
+    let mut exclude = vec![];
+    if let Some(exclude_percent) = config.exclude_percent {
+        exclude.extend(get_percent_exclude(exclude_percent, system.group_processor_count));
+    }
+    if let Some(count) = config.balance_exclude {
+        exclude.extend(get_balance_exclude(count, system.group_processor_count));
+    }
+    exclude
+}
+
+// The number of cores is rounded up, so any percentage above 0 excludes at least one core, but the last core is never
+// excluded, so the process always has somewhere to run.
+fn get_percent_exclude(exclude_percent: f64, processor_count: u32) -> Vec<u32> {
+    let count = (processor_count as f64 * exclude_percent / 100.0).ceil() as u32;
+    (0..count.min(processor_count.saturating_sub(1))).collect()
+}
+
+// Like exclude_percent, the last core is never excluded. Spreading out one core fewer than there are always leaves a gap.
+fn get_balance_exclude(count: u32, processor_count: u32) -> Vec<u32> {
+    spread_indices(count.min(processor_count.saturating_sub(1)), processor_count)
+}
+
+/// Picks `count` indices spread evenly across `0..total`, starting at 0, like `[0, 4, 8, 12]` for 4 of 16. Every
+/// index is returned if `count` is at least `total`.
+pub fn spread_indices(count: u32, total: u32) -> Vec<u32> {
+    let count = count.min(total);
+    (0..count).map(|i| (i as u64 * total as u64 / count as u64) as u32).collect()
+}
+
+// How long auto_exclude_busy watches the cores for. Long enough to smooth out the scheduler, short enough not to hold
+// up the affinity.
+const BUSY_SAMPLE_MILLISECONDS: u32 = 500;
+
+/// The load of each logical processor in the current processor group over `sample_ms` milliseconds, from 0.0 for idle
+/// to 1.0 for busy the whole time. This sleeps for `sample_ms`.
+pub fn measure_core_load(sample_ms: u32) -> std::io::Result<Vec<f64>> {
+    let before = get_processor_times()?;
+    std::thread::sleep(Duration::from_millis(sample_ms as u64));
+    let after = get_processor_times()?;
+    Ok(get_core_loads(&before, &after))
+}
+
+fn get_processor_times() -> std::io::Result<Vec<SYSTEM_PROCESSOR_PERFORMANCE_INFORMATION>> {
+    // Only the processors of the current group are returned.
+    let processor_count = current_group_processor_count();
+    let mut times = vec![SYSTEM_PROCESSOR_PERFORMANCE_INFORMATION::default(); processor_count as usize];
+    let mut length = 0;
+    let status = unsafe {
+        NtQuerySystemInformation(
+            SYSTEM_PROCESSOR_PERFORMANCE_INFORMATION_CLASS,
+            times.as_mut_ptr() as *mut std::ffi::c_void,
+            std::mem::size_of_val(times.as_slice()) as u32,
+            &mut length,
+        )
+    };
+    if status < 0 {
+        return Err(Error::other(format!("Unable to get processor times. NTSTATUS: {status:#x}")));
+    }
+    times.truncate(length as usize / std::mem::size_of::<SYSTEM_PROCESSOR_PERFORMANCE_INFORMATION>());
+
+    Ok(times)
+}
+
+// The share of the time between the two samples that each processor wasn't idle.
+fn get_core_loads(
+    before: &[SYSTEM_PROCESSOR_PERFORMANCE_INFORMATION],
+    after: &[SYSTEM_PROCESSOR_PERFORMANCE_INFORMATION],
+) -> Vec<f64> {
+    before
+        .iter()
+        .zip(after)
+        .map(|(before, after)| {
+            let total = (after.KernelTime - before.KernelTime) + (after.UserTime - before.UserTime);
+            let idle = after.IdleTime - before.IdleTime;
+            if total <= 0 {
+                return 0.0;
+            }
+            (1.0 - idle as f64 / total as f64).clamp(0.0, 1.0)
+        })
+        .collect()
+}
+
+// How many rounds of the loop each core runs in benchmark_cores. Long enough to time, short enough that a CPU with a
+// lot of cores is done in a few seconds.
+const BENCHMARK_ITERATIONS: u64 = 20_000_000;
+
+/// Runs the same short compute loop on each core the process can run on, one core at a time, and returns how fast each
+/// one was, in millions of rounds per second. Slow cores, like E-cores or cores that are throttling, stand out. The
+/// calling thread is pinned to each core in turn, and gets its own affinity back afterwards.
+pub fn benchmark_cores() -> Vec<(u32, f64)> {
+    let mut process_affinity_mask = 0;
+    let mut system_affinity_mask = 0;
+    if !unsafe { GetProcessAffinityMask(self_process_handle(), &mut process_affinity_mask, &mut system_affinity_mask) } {
+        log!("Unable to get process affinity mask. Last Error: {}", format_last_error(unsafe { GetLastError() }));
+        return vec![];
+    }
+
+    let thread = unsafe { GetCurrentThread() };
+    // The first call returns the affinity the thread had before, which is what it gets back.
+    let mut previous = None;
+    let mut results = vec![];
+    for core in mask_to_core_list(process_affinity_mask) {
+        let old_mask = unsafe { SetThreadAffinityMask(thread, 1 << core) };
+        if old_mask == 0 {
+            log!("Unable to run on core {core}. Last Error: {}", format_last_error(unsafe { GetLastError() }));
+            continue;
+        }
+        previous.get_or_insert(old_mask);
+
+        let start = Instant::now();
+        std::hint::black_box(run_benchmark_loop(BENCHMARK_ITERATIONS));
+        results.push((core, BENCHMARK_ITERATIONS as f64 / start.elapsed().as_secs_f64() / 1_000_000.0));
+    }
+    if let Some(previous) = previous {
+        unsafe { SetThreadAffinityMask(thread, previous) };
+    }
+
+    results
+}
+
+// A xorshift, where every round depends on the last one, so it can't be vectorized or skipped.
+fn run_benchmark_loop(iterations: u64) -> u64 {
+    let mut x = std::hint::black_box(0x2545_f491_4f6c_dd1d_u64);
+    for _ in 0..iterations {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+    }
+    x
+}
+
+// Fastest first, with each core compared to the fastest one.
+fn format_benchmark(results: &[(u32, f64)]) -> String {
+    let mut results = results.to_vec();
+    results.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let fastest = results.first().map_or(0.0, |&(_, speed)| speed);
+    let mut table = "Core benchmark, fastest first:".to_string();
+    for (core, speed) in results {
+        table.push_str(&format!("\n  Core {core:>2}: {speed:>8.1} M/s {:>5.1}%", speed / fastest * 100.0));
+    }
+    table
+}
+
+// The `count` busiest cores, busiest first, leaving out the ones in `skip`. At least one core is always left over.
+fn get_busiest_cores(loads: &[f64], count: u32, skip: &[u32]) -> Vec<u32> {
+    let mut cores: Vec<u32> = (0..loads.len() as u32).filter(|core| !skip.contains(core)).collect();
+    cores.sort_by(|&a, &b| loads[b as usize].total_cmp(&loads[a as usize]));
+    let count = (count as usize).min(cores.len().saturating_sub(1));
+    cores.truncate(count);
+    cores
+}
+
+// The lowest logical processor of each physical core, so SMT siblings are left out. The processors of the cores are
+// numbered across every processor group, so only the ones in the group starting at `group_start` are kept, as bits of
+// a mask for that group.
+fn smt_primary_mask(cores: &[ProcessorCore], group_start: u32, group_size: u32) -> usize {
+    cores
+        .iter()
+        .filter_map(|core| core.processors.iter().min())
+        .filter(|&&processor| processor >= group_start && processor - group_start < group_size.min(usize::BITS))
+        .fold(0, |mask, processor| mask | 1 << (processor - group_start))
+}
+
+// The affinity mask only covers the processor group of the process, which is the group this thread runs in.
+fn get_smt_primary_mask() -> std::io::Result<usize> {
+    let cores = get_processor_cores()?;
+    let mut group_affinity = GROUP_AFFINITY { Mask: 0, Group: 0, Reserved: [0; 3] };
+    if !unsafe { GetThreadGroupAffinity(GetCurrentThread(), &mut group_affinity) } {
+        return Err(Error::other(format!(
+            "Unable to get thread group affinity. Last Error: {}",
+            format_last_error(unsafe { GetLastError() })
+        )));
+    }
+
+    let group_sizes = get_group_sizes();
+    let group = group_affinity.Group as usize;
+    let group_start = group_sizes.iter().take(group).sum();
+    Ok(smt_primary_mask(&cores, group_start, group_sizes.get(group).copied().unwrap_or_default()))
+}
+
+const PRESETS: [&str; 3] = ["reserve_first_core", "reserve_smt_siblings", "reserve_half"];
+
+// Returns the mask of the logical processors a preset excludes, from the physical cores of this machine:
+//   reserve_first_core    Every logical processor of the first physical core.
+//   reserve_smt_siblings  Every logical processor but the first on each physical core, so there is one per core.
+//   reserve_half          Every logical processor of the first half of the physical cores, rounded down.
+fn resolve_preset(name: &str, cores: &[ProcessorCore]) -> std::io::Result<usize> {
+    let excluded: Vec<u32> = match name {
+        "reserve_first_core" => cores.iter().take(1).flat_map(|core| core.processors.iter().copied()).collect(),
+        "reserve_smt_siblings" => cores.iter().flat_map(|core| core.processors.iter().skip(1).copied()).collect(),
+        "reserve_half" => {
+            cores.iter().take(cores.len() / 2).flat_map(|core| core.processors.iter().copied()).collect()
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown preset \"{name}\". Expected one of: {}", PRESETS.join(", ")),
+            ))
+        }
+    };
+
+    // Only the logical processors in the affinity mask can be excluded.
+    Ok(excluded.iter().filter(|&&processor| processor < usize::BITS).fold(0, |mask, processor| mask | 1 << processor))
+}
+
+/// The number of logical processors in the current processor group, which is at most 64. This is what an affinity mask
+/// covers.
+pub fn current_group_processor_count() -> u32 {
+    let mut system_info: SYSTEM_INFO = unsafe { std::mem::zeroed() };
+    unsafe { GetSystemInfo(&mut system_info) };
+    system_info.dwNumberOfProcessors
+}
+
+/// The number of logical processors in the current processor group.
+#[deprecated(note = "use current_group_processor_count, or total_logical_processors for every processor group")]
+pub fn logical_processor_count() -> u32 {
+    current_group_processor_count()
+}
+
+/// The number of logical processors across every processor group, which can be more than 64.
+pub fn total_logical_processors() -> u32 {
+    count_logical_processors(unsafe { GetActiveProcessorGroupCount() }, |group| unsafe {
+        GetActiveProcessorCount(group)
+    })
+}
+
+// If the group count can't be read, there is at least the current group.
+fn count_logical_processors(group_count: u16, group_size: impl Fn(u16) -> u32) -> u32 {
+    if group_count == 0 {
+        return current_group_processor_count();
+    }
+
+    (0..group_count).map(group_size).fold(0, u32::saturating_add)
+}
+
+// A core index past the last logical processor doesn't do anything, and is usually a typo, so it gets a warning and
+// is dropped. In strict mode, it is an error instead.
+fn check_core_indices(name: &str, cores: &[u32], processor_count: u32, strict: bool) -> std::io::Result<Vec<u32>> {
+    let (valid, invalid): (Vec<u32>, Vec<u32>) = cores.iter().partition(|&&core| core < processor_count);
+    if invalid.is_empty() {
+        return Ok(valid);
+    }
+
+    let message = format!(
+        "Cores {invalid:?} in {name} don't exist. This system has {processor_count} logical processors (0-{}).",
+        processor_count.saturating_sub(1)
+    );
+    if strict {
+        return Err(Error::new(ErrorKind::InvalidData, message));
+    }
+
+    log!("{message} Ignoring them.");
+    Ok(valid)
+}
+
+// Set when the dll is unloaded, or the process exits. The affinity thread can still be waiting for the delay at that
+// point, and it shouldn't change the affinity after the original was restored, so it checks this before it does.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+fn shutting_down() -> bool {
+    let shutting_down = SHUTTING_DOWN.load(Ordering::SeqCst);
+    if shutting_down {
+        log!("The dll is being unloaded, so the affinity is left alone");
+    }
+    shutting_down
+}
+
+// The mask the control pipe last set, or 0 if it hasn't set one since the affinity thread last looked. A mask is never
+// 0, since it has to have at least one core.
+static CONTROL_PIPE_MASK: AtomicUsize = AtomicUsize::new(0);
+
+fn set_control_pipe_mask(mask: usize) {
+    CONTROL_PIPE_MASK.store(mask, Ordering::SeqCst);
+}
+
+fn take_control_pipe_mask() -> Option<usize> {
+    Some(CONTROL_PIPE_MASK.swap(0, Ordering::SeqCst)).filter(|&mask| mask != 0)
+}
+
+// The last time the affinity thread tried to set the affinity, encoded for get_last_affinity_result, and the whole mask
+// that was set, for get_last_affinity_mask, since a mask with every core of a 64 core group doesn't fit next to the
+// status. They are kept under one lock, so the result of one attempt is never read with the mask of another.
+static LAST_AFFINITY: Mutex<(u64, usize)> = Mutex::new((RESULT_PENDING, 0));
+
+const RESULT_PENDING: u64 = 0;
+const RESULT_APPLIED: u64 = 1;
+const RESULT_UNCHANGED: u64 = 2;
+const RESULT_FAILED: u64 = 3;
+// The status is in the top byte, and the error code or the mask in the rest.
+const RESULT_STATUS_SHIFT: u32 = 56;
+
+fn encode_affinity_result(status: u64, value: u64) -> u64 {
+    status << RESULT_STATUS_SHIFT | value & ((1 << RESULT_STATUS_SHIFT) - 1)
+}
+
+fn set_last_affinity_result(status: u64, value: u64) {
+    *LAST_AFFINITY.lock().unwrap_or_else(PoisonError::into_inner) = (encode_affinity_result(status, value), 0);
+}
+
+fn set_last_affinity_applied(mask: usize) {
+    *LAST_AFFINITY.lock().unwrap_or_else(PoisonError::into_inner) =
+        (encode_affinity_result(RESULT_APPLIED, mask as u64), mask);
+}
+
+// The Windows error behind a failure, or 0 if it didn't come from Windows.
+fn get_error_code(error: &AffinityError) -> u64 {
+    match error {
+        AffinityError::OpenProcessFailed(last_error)
+        | AffinityError::QueryFailed(last_error)
+        | AffinityError::SetFailed { last_error, .. } => *last_error as u64,
+        AffinityError::ConstrainedByJob { .. } => ERROR_ACCESS_DENIED as u64,
+        _ => 0,
+    }
+}
+
+/// What happened the last time the dll set the affinity, so a launcher can check it without reading the log. The top
+/// byte is the status:
+/// - 0: the affinity hasn't been set yet.
+/// - 1: the affinity was set. The lower 56 bits are the mask that was set, without cores 56 to 63, which
+///   [`get_last_affinity_mask`] returns too.
+/// - 2: the affinity was left unchanged on purpose, like in a dry run or when CPU 0 was already off.
+/// - 3: setting the affinity failed. The lower 56 bits are the Windows error code, or 0 if there isn't one.
+#[no_mangle]
+pub extern "C" fn get_last_affinity_result() -> u64 {
+    LAST_AFFINITY.lock().unwrap_or_else(PoisonError::into_inner).0
+}
+
+/// The whole mask that was set, when [`get_last_affinity_result`] has status 1, or 0 otherwise. It is also 0 when the
+/// affinity was set per thread with processor groups but without a group in the config.
+#[no_mangle]
+pub extern "C" fn get_last_affinity_mask() -> usize {
+    LAST_AFFINITY.lock().unwrap_or_else(PoisonError::into_inner).1
+}
+
+const APPLY_TOML_APPLIED: i32 = 0;
+const APPLY_TOML_INVALID_POINTER: i32 = 1;
+const APPLY_TOML_INVALID_UTF8: i32 = 2;
+const APPLY_TOML_PARSE_FAILED: i32 = 3;
+const APPLY_TOML_INVALID_CONFIG: i32 = 4;
+const APPLY_TOML_UNCHANGED: i32 = 5;
+const APPLY_TOML_FAILED: i32 = 6;
+
+/// Parses `len` bytes of toml at `ptr` as a config, and applies it to the current process right away, ignoring the
+/// delay. Returns:
+/// - 0: the affinity was set.
+/// - 1: `ptr` is null.
+/// - 2: the config isn't UTF-8.
+/// - 3: the config isn't valid toml, or has an option with the wrong type.
+/// - 4: the options don't make sense together, like excluding every core.
+/// - 5: the affinity was left alone on purpose, like in a dry run, with `enabled = false`, or when CPU 0 was already
+///   off.
+/// - 6: setting the affinity failed.
+///
+/// A config with `stages` is applied as it is once every stage has run, so only the last stage is used.
+///
+/// This blocks until the config is applied, which includes any retries and the sample for `auto_exclude_busy`, so it
+/// must not be called from `DllMain`. It can be called from any other thread, but the affinity thread of the dll and
+/// the control pipe don't wait for it, so whichever sets the affinity last wins.
+///
+/// # Safety
+/// `ptr` must point to `len` readable bytes, which aren't changed until this returns.
+#[no_mangle]
+pub unsafe extern "C" fn apply_affinity_toml(ptr: *const u8, len: usize) -> i32 {
+    if ptr.is_null() || len > isize::MAX as usize {
+        return APPLY_TOML_INVALID_POINTER;
+    }
+    let Ok(contents) = std::str::from_utf8(std::slice::from_raw_parts(ptr, len)) else {
+        return APPLY_TOML_INVALID_UTF8;
+    };
+    let config: Config = match toml::from_str(contents) {
+        Ok(config) => config,
+        Err(e) => {
+            log!("Could not parse the config passed to apply_affinity_toml: {e}");
+            return APPLY_TOML_PARSE_FAILED;
+        }
+    };
+
+    let system = SystemInfo::query();
+    let resolved = config.validate(&system).map_err(Error::from).and_then(|()| resolve_config(config, &system));
+    let (config, priority) = match resolved {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            log!("Could not apply the config passed to apply_affinity_toml: {e}");
+            return APPLY_TOML_INVALID_CONFIG;
+        }
+    };
+    if !config.enabled {
+        log!("Affinity optimization disabled via the config passed to apply_affinity_toml");
+        return APPLY_TOML_UNCHANGED;
+    }
+    // It is applied right away, so there is no waiting for the stages in between.
+    let config = config.settled();
+    let context = match AffinityContext::new() {
+        Ok(context) => context,
+        Err(e) => {
+            log!("Could not get process affinity. Leaving affinity unchanged. {e}");
+            return APPLY_TOML_FAILED;
+        }
+    };
+
+    match apply_config(&context, &config, &get_config_exclude(&context, &config), priority) {
+        AffinityOutcome::Applied(_) => APPLY_TOML_APPLIED,
+        AffinityOutcome::Unchanged(_) => APPLY_TOML_UNCHANGED,
+        AffinityOutcome::Failed => APPLY_TOML_FAILED,
+    }
+}
+
+// The affinity.toml that comes with the dll, with a comment for every option and the same values as Config::default().
+const DEFAULT_CONFIG: &str = include_str!("../affinity.toml");
+
+/// Writes the `affinity.toml` that comes with the dll to `path`, with a comment for every option, as a starting point
+/// for a new config. Its values are the same as [`Config::default`]. A file that is already at `path` is left alone,
+/// and the error is `AlreadyExists`.
+pub fn write_default_config(path: &Path) -> std::io::Result<()> {
+    use std::io::Write;
+
+    fs::OpenOptions::new().write(true).create_new(true).open(path)?.write_all(DEFAULT_CONFIG.as_bytes())
+}
+
+const WRITE_CONFIG_WRITTEN: i32 = 0;
+const WRITE_CONFIG_INVALID_POINTER: i32 = 1;
+const WRITE_CONFIG_INVALID_UTF8: i32 = 2;
+const WRITE_CONFIG_EXISTS: i32 = 3;
+const WRITE_CONFIG_FAILED: i32 = 4;
+
+/// Writes the default config, like [`write_default_config`], to the UTF-8 path of `len` bytes at `ptr`. Returns:
+/// - 0: the config was written.
+/// - 1: `ptr` is null.
+/// - 2: the path isn't UTF-8.
+/// - 3: there already is a file at the path, and it was left alone.
+/// - 4: the file couldn't be written, like when the folder doesn't exist.
+///
+/// # Safety
+/// `ptr` must point to `len` readable bytes, which aren't changed until this returns.
+#[no_mangle]
+pub unsafe extern "C" fn write_default_config_file(ptr: *const u8, len: usize) -> i32 {
+    if ptr.is_null() || len > isize::MAX as usize {
+        return WRITE_CONFIG_INVALID_POINTER;
+    }
+    let Ok(path) = std::str::from_utf8(std::slice::from_raw_parts(ptr, len)) else {
+        return WRITE_CONFIG_INVALID_UTF8;
+    };
+
+    match write_default_config(Path::new(path)) {
+        Ok(()) => {
+            log!("Wrote the default config to {path}");
+            WRITE_CONFIG_WRITTEN
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => WRITE_CONFIG_EXISTS,
+        Err(e) => {
+            log!("Could not write the default config to {path}: {e}");
+            WRITE_CONFIG_FAILED
+        }
+    }
+}
+
+// The affinity mask the process had before we first changed it. 0 means we haven't changed anything.
+static ORIGINAL_AFFINITY_MASK: AtomicUsize = AtomicUsize::new(0);
+// Set once default CPU Sets have been set for the process, so they are cleared again when the affinity is restored.
+static CPU_SETS_APPLIED: AtomicBool = AtomicBool::new(false);
+
+// The pseudo-handle for the current process. It is always valid, so there is no OpenProcess call that can be denied,
+// and it must not be closed.
+fn self_process_handle() -> HANDLE {
+    unsafe { GetCurrentProcess() }
+}
+
+// Opens the process with only the rights needed to get and set its affinity and priority. Some restricted tokens
+// don't grant PROCESS_QUERY_INFORMATION, so this falls back to PROCESS_QUERY_LIMITED_INFORMATION, which is enough to
+// read the affinity mask.
+fn open_process(pid: u32) -> Result<OwnedHandle, AffinityError> {
+    let mut process_handle = unsafe { OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_SET_INFORMATION, true, pid) };
+    trace!("OpenProcess({pid}) -> {:?}", process_handle.as_ptr());
+    if !process_handle.is_valid() {
+        process_handle =
+            unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION | PROCESS_SET_INFORMATION, true, pid) };
+        trace!("OpenProcess({pid}) with limited information -> {:?}", process_handle.as_ptr());
+    }
+
+    let process_handle = unsafe { OwnedHandle::new(process_handle) };
+    if !process_handle.is_valid() {
+        return Err(AffinityError::OpenProcessFailed(unsafe { GetLastError() }));
+    }
+
+    Ok(process_handle)
+}
+
+/// Sets the affinity of the process with the given PID to `mask`, limited to the cores the system has.
+///
+/// ```no_run
+/// use dinput8::{get_exclude_mask, set_affinity_for_pid};
+///
+/// let pid = 1234;
+/// // Let the process run on every core except core 0.
+/// set_affinity_for_pid(pid, !get_exclude_mask(&[0])).expect("Could not set affinity");
+/// ```
+pub fn set_affinity_for_pid(pid: u32, mask: usize) -> Result<(), AffinityError> {
+    let process_handle = open_process(pid)?;
+
+    let mut process_affinity_mask = 0;
+    let mut system_affinity_mask = 0;
+    let succeeded =
+        unsafe { GetProcessAffinityMask(*process_handle, &mut process_affinity_mask, &mut system_affinity_mask) };
+    let last_error = unsafe { GetLastError() };
+    trace!(
+        "GetProcessAffinityMask -> {}, proc={process_affinity_mask:#b}, sys={system_affinity_mask:#b}",
+        trace_result(succeeded, last_error)
+    );
+    if !succeeded {
+        return Err(AffinityError::QueryFailed(last_error));
+    }
+
+    let new_mask = mask & system_affinity_mask;
+    if new_mask == 0 {
+        return Err(AffinityError::NoCoresLeft {
+            include: mask,
+            exclude: 0,
+            system: system_affinity_mask,
+        });
+    }
+
+    let succeeded = unsafe { SetProcessAffinityMask(*process_handle, new_mask) };
+    let last_error = unsafe { GetLastError() };
+    trace!("SetProcessAffinityMask({new_mask:#b}) -> {}", trace_result(succeeded, last_error));
+    if !succeeded {
+        return Err(get_set_error(*process_handle, pid == unsafe { GetCurrentProcessId() }, new_mask, last_error));
+    }
+
+    Ok(())
+}
+
+// A job object that limits affinity makes SetProcessAffinityMask fail with ERROR_ACCESS_DENIED even though the handle
+// has every right it needs, so that case gets its own error instead of looking like a permissions problem. The job's
+// own mask can only be read for the current process, since a null job handle means the job of the caller, so for it,
+// a job that doesn't limit affinity is left out. Launchers put games in jobs for all sorts of other reasons.
+fn get_set_error(process_handle: HANDLE, own_process: bool, requested: usize, last_error: u32) -> AffinityError {
+    let mut in_job = 0;
+    if last_error != ERROR_ACCESS_DENIED
+        || !unsafe { IsProcessInJob(process_handle, HANDLE::null(), &mut in_job) }
+        || in_job == 0
+    {
+        return AffinityError::SetFailed { requested, last_error };
+    }
+
+    let job_affinity = if own_process { get_job_affinity() } else { None };
+    trace!("IsProcessInJob -> true, job affinity={job_affinity:?}");
+    if own_process && job_affinity.is_none() {
+        return AffinityError::SetFailed { requested, last_error };
+    }
+    AffinityError::ConstrainedByJob { requested, job_affinity }
+}
+
+// The affinity limit of the job the current process is in, or None if the job doesn't limit it.
+fn get_job_affinity() -> Option<usize> {
+    let mut limits = JOBOBJECT_BASIC_LIMIT_INFORMATION::default();
+    let succeeded = unsafe {
+        QueryInformationJobObject(
+            HANDLE::null(),
+            JOB_OBJECT_BASIC_LIMIT_INFORMATION_CLASS,
+            &mut limits as *mut _ as *mut std::ffi::c_void,
+            std::mem::size_of::<JOBOBJECT_BASIC_LIMIT_INFORMATION>() as u32,
+            std::ptr::null_mut(),
+        )
+    };
+    (succeeded && limits.LimitFlags & JOB_OBJECT_LIMIT_AFFINITY != 0).then_some(limits.Affinity)
+}
+
+fn get_process_affinity_mask(context: &AffinityContext) -> Result<usize, AffinityError> {
+    context.get_mask().map(|(process_affinity_mask, _)| process_affinity_mask)
+}
+
+/// The calls [`set_processor_affinity_with`] makes to read and change the affinity of a process, so the logic that
+/// decides the new mask can be used, and tested, without the Win32 API.
+pub trait AffinityApi {
+    /// Returns the process affinity mask and the system affinity mask.
+    fn get_mask(&self) -> Result<(usize, usize), AffinityError>;
+    /// Sets the process affinity mask.
+    fn set_mask(&self, mask: usize) -> Result<(), AffinityError>;
+}
+
+impl<A: AffinityApi + ?Sized> AffinityApi for &A {
+    fn get_mask(&self) -> Result<(usize, usize), AffinityError> {
+        (**self).get_mask()
+    }
+
+    fn set_mask(&self, mask: usize) -> Result<(), AffinityError> {
+        (**self).set_mask(mask)
+    }
+}
+
+/// Reads and changes the affinity of the current process with the Win32 API. The process handle, system affinity mask
+/// and logical processor count don't change while the process runs, so they are queried once when this is created,
+/// and reused every time the affinity is set. The same goes for the name and ID of the process, which are used in logs.
+#[derive(Clone)]
+pub struct AffinityContext {
+    process_handle: HANDLE,
+    system_affinity_mask: usize,
+    processor_count: u32,
+    group_processor_count: u32,
+    process_name: String,
+    process_id: u32,
+}
+
+// The handle is the GetCurrentProcess pseudo-handle, which means the current process on any thread, and is never
+// closed.
+unsafe impl Send for AffinityContext {}
+
+impl AffinityContext {
+    pub fn new() -> Result<Self, AffinityError> {
+        let process_handle = self_process_handle();
+
+        let mut process_affinity_mask = 0;
+        let mut system_affinity_mask = 0;
+        let succeeded =
+            unsafe { GetProcessAffinityMask(process_handle, &mut process_affinity_mask, &mut system_affinity_mask) };
+        let last_error = unsafe { GetLastError() };
+        trace!(
+            "GetProcessAffinityMask -> {}, proc={process_affinity_mask:#b}, sys={system_affinity_mask:#b}",
+            trace_result(succeeded, last_error)
+        );
+        if !succeeded {
+            return Err(AffinityError::QueryFailed(last_error));
+        }
+
+        Ok(AffinityContext {
+            process_handle,
+            system_affinity_mask,
+            processor_count: total_logical_processors(),
+            group_processor_count: current_group_processor_count(),
+            process_name: get_host_exe_name().unwrap_or_else(|_| "unknown".to_string()),
+            process_id: unsafe { GetCurrentProcessId() },
+        })
+    }
+
+    pub fn system_affinity_mask(&self) -> usize {
+        self.system_affinity_mask
+    }
+
+    pub fn processor_count(&self) -> u32 {
+        self.processor_count
+    }
+
+    /// The number of logical processors in the current processor group, which is what the affinity mask covers.
+    pub fn group_processor_count(&self) -> u32 {
+        self.group_processor_count
+    }
+
+    /// The same system as [`SystemInfo::query`], without querying it again.
+    pub fn system_info(&self) -> SystemInfo {
+        SystemInfo {
+            processor_count: self.processor_count,
+            group_processor_count: self.group_processor_count,
+            system_affinity_mask: self.system_affinity_mask,
+        }
+    }
+
+    /// The file name of the executable, like `game.exe`.
+    pub fn process_name(&self) -> &str {
+        &self.process_name
+    }
+
+    pub fn process_id(&self) -> u32 {
+        self.process_id
+    }
+}
+
+impl AffinityApi for AffinityContext {
+    fn get_mask(&self) -> Result<(usize, usize), AffinityError> {
+        let mut process_affinity_mask = 0;
+        let mut system_affinity_mask = 0;
+        let succeeded = unsafe {
+            GetProcessAffinityMask(self.process_handle, &mut process_affinity_mask, &mut system_affinity_mask)
+        };
+        let last_error = unsafe { GetLastError() };
+        trace!(
+            "GetProcessAffinityMask -> {}, proc={process_affinity_mask:#b}, sys={system_affinity_mask:#b}",
+            trace_result(succeeded, last_error)
+        );
+        if !succeeded {
+            return Err(AffinityError::QueryFailed(last_error));
+        }
+
+        Ok((process_affinity_mask, self.system_affinity_mask))
+    }
+
+    fn set_mask(&self, mask: usize) -> Result<(), AffinityError> {
+        let (original_mask, _) = self.get_mask()?;
+        let succeeded = unsafe { SetProcessAffinityMask(self.process_handle, mask) };
+        let last_error = unsafe { GetLastError() };
+        trace!("SetProcessAffinityMask({mask:#b}) -> {}", trace_result(succeeded, last_error));
+        if !succeeded {
+            return Err(get_set_error(self.process_handle, true, mask, last_error));
+        }
+
+        // Only keep the first mask we saw, so setting the affinity more than once still restores the real original.
+        let _ = ORIGINAL_AFFINITY_MASK.compare_exchange(0, original_mask, Ordering::SeqCst, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+// Runs every check on the mask, but only logs the mask that would be set instead of setting it. The mask is kept, so
+// reading it back afterwards sees what a real set would have done.
+struct DryRunAffinity<'a> {
+    context: &'a AffinityContext,
+    mask: std::cell::Cell<Option<usize>>,
+}
+
+impl AffinityApi for DryRunAffinity<'_> {
+    fn get_mask(&self) -> Result<(usize, usize), AffinityError> {
+        let (process_affinity_mask, system_affinity_mask) = self.context.get_mask()?;
+        Ok((self.mask.get().unwrap_or(process_affinity_mask), system_affinity_mask))
+    }
+
+    fn set_mask(&self, mask: usize) -> Result<(), AffinityError> {
+        log!("Dry run: would set affinity to {}", describe_mask(mask));
+        self.mask.set(Some(mask));
+        Ok(())
+    }
+}
+
+// Sets the default CPU Sets of the process instead of its affinity mask. CPU Sets don't change the affinity mask, so
+// the mask is kept the same way DryRunAffinity keeps it, and reading it back sees the cores that are in the CPU Sets.
+struct CpuSetAffinity<'a> {
+    context: &'a AffinityContext,
+    mask: std::cell::Cell<Option<usize>>,
+}
+
+impl AffinityApi for CpuSetAffinity<'_> {
+    fn get_mask(&self) -> Result<(usize, usize), AffinityError> {
+        let (process_affinity_mask, system_affinity_mask) = self.context.get_mask()?;
+        Ok((self.mask.get().unwrap_or(process_affinity_mask), system_affinity_mask))
+    }
+
+    fn set_mask(&self, mask: usize) -> Result<(), AffinityError> {
+        let (Some(get_system_cpu_set_information), Some(set_process_default_cpu_sets)) =
+            (get_cpu_set_information_function(), get_set_cpu_sets_function())
+        else {
+            log!("CPU Sets aren't available on this version of Windows, so the affinity mask is set instead");
+            return self.context.set_mask(mask);
+        };
+
+        let process_handle = self.context.process_handle;
+        let mut length = 0;
+        if !unsafe { get_system_cpu_set_information(std::ptr::null_mut(), 0, &mut length, process_handle, 0) }
+            && unsafe { GetLastError() } != ERROR_INSUFFICIENT_BUFFER
+        {
+            return Err(AffinityError::QueryFailed(unsafe { GetLastError() }));
+        }
+        let mut buffer = vec![0u8; length as usize];
+        if !unsafe { get_system_cpu_set_information(buffer.as_mut_ptr(), length, &mut length, process_handle, 0) } {
+            return Err(AffinityError::QueryFailed(unsafe { GetLastError() }));
+        }
+        buffer.truncate(length as usize);
+
+        let cpu_set_ids = get_cpu_set_ids(&buffer, mask);
+        log!("Setting default CPU Sets to {cpu_set_ids:?}");
+        let succeeded =
+            unsafe { set_process_default_cpu_sets(process_handle, cpu_set_ids.as_ptr(), cpu_set_ids.len() as u32) };
+        let last_error = unsafe { GetLastError() };
+        trace!("SetProcessDefaultCpuSets({cpu_set_ids:?}) -> {}", trace_result(succeeded, last_error));
+        if !succeeded {
+            return Err(AffinityError::SetFailed {
+                requested: mask,
+                last_error,
+            });
+        }
+
+        CPU_SETS_APPLIED.store(true, Ordering::SeqCst);
+        self.mask.set(Some(mask));
+        Ok(())
+    }
+}
+
+fn get_cpu_set_information_function() -> Option<GetSystemCpuSetInformationFn> {
+    let function = get_kernel32_function(c"GetSystemCpuSetInformation");
+    (!function.is_null()).then(|| unsafe { std::mem::transmute(function) })
+}
+
+fn get_set_cpu_sets_function() -> Option<SetProcessDefaultCpuSetsFn> {
+    let function = get_kernel32_function(c"SetProcessDefaultCpuSets");
+    (!function.is_null()).then(|| unsafe { std::mem::transmute(function) })
+}
+
+// Parses the SYSTEM_CPU_SET_INFORMATION records returned by GetSystemCpuSetInformation, and returns the ID of each CPU
+// Set for a core in `mask`. Like the affinity mask, this only covers the cores in processor group 0.
+fn get_cpu_set_ids(buffer: &[u8], mask: usize) -> Vec<u32> {
+    let mut ids = vec![];
+    let mut offset = 0;
+    while offset + std::mem::size_of::<SYSTEM_CPU_SET_INFORMATION>() <= buffer.len() {
+        let cpu_set =
+            unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr() as *const SYSTEM_CPU_SET_INFORMATION) };
+        if cpu_set.Size == 0 {
+            break;
+        }
+        offset += cpu_set.Size as usize;
+
+        let core = cpu_set.LogicalProcessorIndex as u32;
+        if cpu_set.Type == CPU_SET_INFORMATION && cpu_set.Group == 0 && core < usize::BITS && mask & (1 << core) != 0 {
+            ids.push(cpu_set.Id);
+        }
+    }
+
+    ids
+}
+
+// Sets the affinity of only the threads with a name matching `filter`, and leaves the process affinity, and so every
+// other thread, alone. The mask is kept the same way DryRunAffinity keeps it, since the process mask doesn't change.
+struct ThreadNameAffinity<'a> {
+    context: &'a AffinityContext,
+    filter: &'a str,
+    mask: std::cell::Cell<Option<usize>>,
+}
+
+impl AffinityApi for ThreadNameAffinity<'_> {
+    fn get_mask(&self) -> Result<(usize, usize), AffinityError> {
+        let (process_affinity_mask, system_affinity_mask) = self.context.get_mask()?;
+        Ok((self.mask.get().unwrap_or(process_affinity_mask), system_affinity_mask))
+    }
+
+    fn set_mask(&self, mask: usize) -> Result<(), AffinityError> {
+        // Without a mask, reading it back sees the process mask, so a failure here ends up as NotApplied.
+        match set_named_thread_affinity(self.filter, mask) {
+            Ok((matched, updated)) => {
+                log!("Changed affinity for {updated} of the {matched} threads named like \"{}\"", self.filter);
+                self.mask.set(Some(mask));
+            }
+            Err(e) => log!("Could not set affinity of threads named like \"{}\": {e}", self.filter),
+        }
+        Ok(())
+    }
+}
+
+fn get_affinity_api<'a>(context: &'a AffinityContext, config: &'a Config) -> Box<dyn AffinityApi + 'a> {
+    if config.dry_run {
+        return Box::new(DryRunAffinity { context, mask: Default::default() });
+    }
+    if let Some(filter) = &config.thread_name_filter {
+        return Box::new(ThreadNameAffinity { context, filter, mask: Default::default() });
+    }
+    if config.use_cpu_sets {
+        return Box::new(CpuSetAffinity { context, mask: Default::default() });
+    }
+
+    Box::new(context)
+}
+
+/// Sets the affinity of the current process, and returns the new affinity mask.
+///
+/// If `include` is set, the process runs on the included cores the system has, minus the `exclude` cores. Otherwise
+/// the `exclude` cores are removed from the current affinity, unless CPU 0 is already off.
+pub fn set_processor_affinity(
+    context: &AffinityContext,
+    include: Option<usize>,
+    exclude: usize,
+) -> Result<usize, AffinityError> {
+    set_processor_affinity_with(context, include, exclude, &AffinityOptions::default())
+}
+
+/// The checks [`set_processor_affinity_with`] makes before changing the affinity.
+#[derive(Debug, Clone)]
+pub struct AffinityOptions {
+    /// When only excluding cores, a process that already has CPU 0 off is assumed to have had its affinity set by
+    /// someone else (the game, a launcher, or an earlier run), and is left alone with
+    /// [`AffinityError::CpuZeroAlreadyOff`]. Set this to false to remove the `exclude` cores from the current affinity
+    /// regardless.
+    pub only_if_cpu0_enabled: bool,
+    /// Refuses, with [`AffinityError::TooFewCores`], to set an affinity with fewer cores than this.
+    pub min_cores: Option<u32>,
+    /// Treats `exclude` as the cores to keep instead, so without `include`, the process keeps only the cores in
+    /// `exclude` that it already has. This doesn't change what `include` does.
+    pub invert: bool,
+    /// How many more times to set the mask if reading it back shows it didn't stick, waiting 10ms before the first
+    /// retry and twice as long before each one after that.
+    pub retries: u32,
+    /// Reads the mask back after setting it, and fails with [`AffinityError::NotApplied`] if it didn't stick. Wine
+    /// doesn't always report the mask that was set, so this is turned off there.
+    pub verify: bool,
+    /// Leaves the process alone with [`AffinityError::AlreadyNarrower`] if its affinity is already a strict subset of
+    /// the cores the config picks, since the game or a launcher most likely chose it on purpose. This doesn't apply
+    /// with `invert`, which only ever keeps cores the process already has.
+    pub respect_existing: bool,
+}
+
+impl Default for AffinityOptions {
+    fn default() -> Self {
+        AffinityOptions {
+            only_if_cpu0_enabled: true,
+            min_cores: None,
+            invert: false,
+            retries: 0,
+            verify: true,
+            respect_existing: false,
+        }
+    }
+}
+
+/// [`set_processor_affinity`], with the affinity read and changed through `api`, and the checks in `options`.
+pub fn set_processor_affinity_with<A: AffinityApi + ?Sized>(
+    api: &A,
+    include: Option<usize>,
+    exclude: usize,
+    options: &AffinityOptions,
+) -> Result<usize, AffinityError> {
+    let (process_affinity_mask, system_affinity_mask) = api.get_mask()?;
+
+    let core_count = current_group_processor_count();
+    log!(
+        "Process affinity mask: {:?}\n{}\nSystem affinity mask: {:?}\n{}",
+        mask_to_core_list(process_affinity_mask),
+        format_mask(process_affinity_mask, core_count),
+        mask_to_core_list(system_affinity_mask),
+        format_mask(system_affinity_mask, core_count)
+    );
+    // Otherwise both of these end up as a new mask of 0, which looks like the config excluded every core.
+    if process_affinity_mask == 0 {
+        return Err(AffinityError::EmptyProcessMask);
+    }
+    if process_affinity_mask.count_ones() == 1 && include.is_none() && exclude != 0 {
+        return Err(AffinityError::SingleCpu);
+    }
+    // Cores can be missing from the process mask when the OS has parked them, or something else set the affinity.
+    if process_affinity_mask != system_affinity_mask {
+        log!(
+            "Cores {:?} are on the system but not available to this process",
+            mask_to_core_list(system_affinity_mask & !process_affinity_mask)
+        );
+    }
+    let missing_exclude = exclude & !system_affinity_mask;
+    if missing_exclude != 0 {
+        log!(
+            "Excluded cores {:?} aren't in the system affinity mask, so excluding them does nothing",
+            mask_to_core_list(missing_exclude)
+        );
+    }
+
+    if options.respect_existing && !options.invert {
+        let requested = include.unwrap_or(system_affinity_mask) & system_affinity_mask & !exclude;
+        if process_affinity_mask != requested && process_affinity_mask & !requested == 0 {
+            return Err(AffinityError::AlreadyNarrower { process: process_affinity_mask, requested });
+        }
+    }
+
+    // Include is applied first (limited to the cores the system has), then the excluded cores are removed from it. The
+    // other cases start from the process mask, which is always within the system mask, so every new mask is too.
+    let clear_mask = !exclude;
+    let new_mask = match include {
+        Some(include) => {
+            let unavailable = include & !system_affinity_mask;
+            if unavailable != 0 {
+                log!(
+                    "Requested cores {:?} aren't in the system affinity mask, so they are left out",
+                    mask_to_core_list(unavailable)
+                );
+            }
+            if include & system_affinity_mask == 0 {
+                return Err(AffinityError::NoAvailableCores {
+                    requested: include,
+                    system: system_affinity_mask,
+                });
+            }
+            include & system_affinity_mask & clear_mask
+        }
+        // The CPU 0 guard is about excluding cores, so it doesn't apply when only the listed cores are kept.
+        None if options.invert => process_affinity_mask & exclude,
+        // Removing nothing would set the mask it already has.
+        None if exclude == 0 => return Err(AffinityError::NothingToExclude),
+        None => {
+            // If CPU 0 is already off, then leave the affinity alone.
+            if options.only_if_cpu0_enabled && process_affinity_mask & 1 != 1 {
+                return Err(AffinityError::CpuZeroAlreadyOff);
+            }
+
+            process_affinity_mask & clear_mask
+        }
+    };
+    if let (0, Some(include)) = (new_mask, include) {
+        return Err(AffinityError::NoCoresLeft {
+            include,
+            exclude,
+            system: system_affinity_mask,
+        });
+    }
+    if new_mask == 0 && options.invert {
+        return Err(AffinityError::NoCoresLeft {
+            include: exclude,
+            exclude: 0,
+            system: process_affinity_mask,
+        });
+    }
+    if new_mask == 0 {
+        return Err(AffinityError::SingleCpu);
+    }
+    let remaining = new_mask.count_ones();
+    if let Some(min_cores) = options.min_cores.filter(|&min_cores| remaining < min_cores) {
+        return Err(AffinityError::TooFewCores { remaining, min_cores });
+    }
+
+    log!("Setting affinity mask to {}", describe_mask(new_mask));
+    let mut backoff = RETRY_BACKOFF;
+    for attempt in 1.. {
+        api.set_mask(new_mask)?;
+        if !options.verify {
+            break;
+        }
+
+        // The OS can clamp the mask we asked for without failing the call, so read it back to make sure it stuck.
+        let (applied_mask, _) = api.get_mask()?;
+
+        log!(
+            "Affinity (attempt {attempt}) before:\n{}\nafter:\n{}",
+            format_mask(process_affinity_mask, core_count),
+            format_mask(applied_mask, core_count)
+        );
+
+        if applied_mask == new_mask {
+            break;
+        }
+        // The game can still be starting threads that change the affinity right after it is set, so try again a
+        // little later, waiting twice as long each time.
+        if attempt > options.retries {
+            return Err(AffinityError::NotApplied {
+                requested: new_mask,
+                applied: applied_mask,
+            });
+        }
+        std::thread::sleep(backoff);
+        backoff *= 2;
+    }
+
+    Ok(new_mask)
+}
+
+// How long to wait before the first retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Sets the affinity mask until it is dropped, and then restores the mask the process had before. Call
+/// [`AffinityScope::forget`] to keep the new mask instead.
+///
+/// ```no_run
+/// let _scope = dinput8::AffinityScope::apply(0b1110)?;
+/// // Runs on cores 1 to 3 until _scope goes out of scope.
+/// # Ok::<(), dinput8::AffinityError>(())
+/// ```
+#[must_use = "the previous affinity is restored as soon as the scope is dropped"]
+pub struct AffinityScope<A: AffinityApi = AffinityContext> {
+    api: A,
+    // None once it has been forgotten.
+    previous: Option<usize>,
+}
+
+impl AffinityScope {
+    /// Sets the affinity mask of the current process.
+    pub fn apply(mask: usize) -> Result<Self, AffinityError> {
+        AffinityScope::apply_with(AffinityContext::new()?, mask)
+    }
+}
+
+impl<A: AffinityApi> AffinityScope<A> {
+    /// [`AffinityScope::apply`], with the affinity read and changed through `api`.
+    pub fn apply_with(api: A, mask: usize) -> Result<Self, AffinityError> {
+        let (previous, _) = api.get_mask()?;
+        api.set_mask(mask)?;
+        Ok(AffinityScope {
+            api,
+            previous: Some(previous),
+        })
+    }
+
+    /// The mask that is restored when the scope is dropped.
+    pub fn previous_mask(&self) -> Option<usize> {
+        self.previous
+    }
+
+    /// Keeps the new mask, instead of restoring the previous one.
+    pub fn forget(mut self) {
+        self.previous = None;
+    }
+}
+
+impl<A: AffinityApi> Drop for AffinityScope<A> {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous {
+            if let Err(e) = self.api.set_mask(previous) {
+                log!("Could not restore affinity mask {}: {e}", describe_mask(previous));
+            }
+        }
+    }
+}
+
+/// The core index of each bit that is set in `mask`, lowest first. The inverse of [`core_list_to_mask`].
+pub fn mask_to_core_list(mask: usize) -> Vec<u32> {
+    (0..usize::BITS).filter(|&core| mask & (1 << core) != 0).collect()
+}
+
+/// Formats `mask` as one bit per core, core 0 first, in groups of four, under a ruler with the first core of each
+/// group. For example, `format_mask(0b11110011, 8)` is `"0    4\n1100 1111"`. Exactly `core_count` cores are shown,
+/// up to `usize::BITS`, so cores the mask has past that are left out. With no cores, it is `"(none)"`.
+pub fn format_mask(mask: usize, core_count: u32) -> String {
+    let bits = get_mask_bits(mask, core_count);
+    if bits.is_empty() {
+        return "(none)".to_string();
+    }
+    let ruler: String = (0..bits.len()).step_by(5).map(|start| format!("{:<5}", start / 5 * 4)).collect();
+    format!("{}\n{bits}", ruler.trim_end())
+}
+
+// Just the bits of format_mask, so a mask fits on one line with the rest of a message.
+fn get_mask_bits(mask: usize, core_count: u32) -> String {
+    let bits: Vec<String> = (0..core_count.min(usize::BITS))
+        .collect::<Vec<_>>()
+        .chunks(4)
+        .map(|group| group.iter().map(|&core| if mask & (1 << core) != 0 { '1' } else { '0' }).collect())
+        .collect();
+    bits.join(" ")
+}
+
+// Masks are printed one bit per core of the group, core 0 first, followed by the cores they have, like
+// "0011 1100 [2, 3, 4, 5]". The list still has any core past the end of the group.
+fn describe_mask(mask: usize) -> String {
+    format!("{} {:?}", get_mask_bits(mask, current_group_processor_count()), mask_to_core_list(mask))
+}
+
+// Threads that set their own affinity keep it when the process affinity changes, so this applies the mask to every
+// thread in the process directly. Returns the number of threads that were updated.
+fn set_thread_affinity(mask: usize) -> std::io::Result<usize> {
+    for_each_thread(|thread_handle| unsafe { SetThreadAffinityMask(thread_handle, mask) } != 0)
+}
+
+// Only a hint to the scheduler, so threads can still run on other cores when the ideal one is busy.
+fn set_thread_ideal_processor(ideal_processor: u32) -> std::io::Result<usize> {
+    for_each_thread(|thread_handle| unsafe { SetThreadIdealProcessor(thread_handle, ideal_processor) } != u32::MAX)
+}
+
+fn set_named_thread_affinity(filter: &str, mask: usize) -> std::io::Result<(usize, usize)> {
+    for_each_named_thread(filter, |thread_handle| unsafe { SetThreadAffinityMask(thread_handle, mask) } != 0)
+}
+
+// Like for_each_thread, but only for the threads with a description matching `filter`. Returns how many threads
+// matched, and how many of those calls returned true. Threads without a description never match.
+fn for_each_named_thread(filter: &str, mut f: impl FnMut(HANDLE) -> bool) -> std::io::Result<(usize, usize)> {
+    let get_thread_description = get_kernel32_function(c"GetThreadDescription");
+    if get_thread_description.is_null() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "GetThreadDescription isn't available on this version of Windows",
+        ));
+    }
+    let get_thread_description: GetThreadDescriptionFn = unsafe { std::mem::transmute(get_thread_description) };
+
+    let mut matched = 0;
+    let updated = for_each_thread(|thread_handle| {
+        match get_thread_name(get_thread_description, thread_handle) {
+            Some(name) if matches_thread_name(filter, &name) => {
+                matched += 1;
+                f(thread_handle)
+            }
+            _ => false,
+        }
+    })?;
+
+    Ok((matched, updated))
+}
+
+fn get_thread_name(get_thread_description: GetThreadDescriptionFn, thread_handle: HANDLE) -> Option<String> {
+    let mut description = std::ptr::null_mut();
+    // A negative HRESULT is an error.
+    if unsafe { get_thread_description(thread_handle, &mut description) } < 0 || description.is_null() {
+        return None;
+    }
+
+    let length = (0..).take_while(|&i| unsafe { *description.add(i) } != 0).count();
+    let name = String::from_utf16_lossy(unsafe { std::slice::from_raw_parts(description, length) });
+    unsafe { LocalFree(description as *mut std::ffi::c_void) };
+    (!name.is_empty()).then_some(name)
+}
+
+// `*` matches any number of characters, and everything else has to match exactly, ignoring case.
+fn matches_thread_name(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let name = name.to_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts = parts.collect::<Vec<_>>();
+    let Some((last, middle)) = parts.split_last() else {
+        // No `*`, so the whole name has to match.
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+// Calls `f` with a handle to each thread in the current process, and returns how many calls returned true.
+fn for_each_thread(mut f: impl FnMut(HANDLE) -> bool) -> std::io::Result<usize> {
+    let pid = unsafe { GetCurrentProcessId() };
+
+    let snapshot = unsafe { OwnedHandle::new(CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0)) };
+    if !snapshot.is_valid() {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Unable to create thread snapshot. Last Error: {}",
+                format_last_error(unsafe { GetLastError() })
+            ),
+        ));
+    }
+
+    let mut entry = THREADENTRY32 {
+        dwSize: std::mem::size_of::<THREADENTRY32>() as u32,
+        ..Default::default()
+    };
+    let mut updated = 0;
+    let mut has_entry = unsafe { Thread32First(*snapshot, &mut entry) };
+    while has_entry {
+        if entry.th32OwnerProcessID == pid {
+            // Threads can exit between the snapshot and here, so failing to open or update one isn't an error.
+            let thread_handle = unsafe {
+                OwnedHandle::new(OpenThread(THREAD_SET_INFORMATION | THREAD_QUERY_INFORMATION, false, entry.th32ThreadID))
+            };
+            if thread_handle.is_valid() && f(*thread_handle) {
+                updated += 1;
+            }
+        }
+
+        has_entry = unsafe { Thread32Next(*snapshot, &mut entry) };
+    }
+
+    Ok(updated)
+}
+
+/// The PIDs of the processes that were started by `parent`.
+pub fn child_pids(parent: u32) -> std::io::Result<Vec<u32>> {
+    let mut processes = vec![];
+    for_each_process(|entry| processes.push((entry.th32ProcessID, entry.th32ParentProcessID)))?;
+    Ok(get_child_pids(&processes, parent))
+}
+
+/// The PIDs of every running process with an executable named `name`, ignoring case. If the running processes can't
+/// be listed, the error is printed and no PIDs are returned.
+///
+/// ```no_run
+/// use dinput8::{find_pids_by_name, get_exclude_mask, set_affinity_for_pid};
+///
+/// for pid in find_pids_by_name("eldenring.exe") {
+///     set_affinity_for_pid(pid, !get_exclude_mask(&[0])).expect("Could not set affinity");
+/// }
+/// ```
+pub fn find_pids_by_name(name: &str) -> Vec<u32> {
+    let mut processes = vec![];
+    let result = for_each_process(|entry| {
+        let length = entry.szExeFile.iter().position(|&c| c == 0).unwrap_or(entry.szExeFile.len());
+        processes.push((entry.th32ProcessID, String::from_utf16_lossy(&entry.szExeFile[..length])));
+    });
+    if let Err(e) = result {
+        log!("Could not find processes named {name}: {e}");
+        return vec![];
+    }
+
+    get_pids_by_name(&processes, name)
+}
+
+// `processes` is (pid, executable name).
+fn get_pids_by_name(processes: &[(u32, String)], name: &str) -> Vec<u32> {
+    processes
+        .iter()
+        .filter(|(_, exe_name)| exe_name.eq_ignore_ascii_case(name))
+        .map(|&(pid, _)| pid)
+        .collect()
+}
+
+// `processes` is (pid, parent pid). Windows reuses PIDs, and the idle process is its own parent, so a process is never
+// its own child.
+fn get_child_pids(processes: &[(u32, u32)], parent: u32) -> Vec<u32> {
+    processes
+        .iter()
+        .filter(|&&(pid, parent_pid)| parent_pid == parent && pid != parent)
+        .map(|&(pid, _)| pid)
+        .collect()
+}
+
+// Calls `f` with each process in a snapshot of the running processes.
+fn for_each_process(mut f: impl FnMut(&PROCESSENTRY32W)) -> std::io::Result<()> {
+    let snapshot = unsafe { OwnedHandle::new(CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0)) };
+    if !snapshot.is_valid() {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Unable to create process snapshot. Last Error: {}",
+                format_last_error(unsafe { GetLastError() })
+            ),
+        ));
+    }
+
+    let mut entry: PROCESSENTRY32W = unsafe { std::mem::zeroed() };
+    entry.dwSize = std::mem::size_of::<PROCESSENTRY32W>() as u32;
+    let mut has_entry = unsafe { Process32FirstW(*snapshot, &mut entry) };
+    while has_entry {
+        f(&entry);
+        has_entry = unsafe { Process32NextW(*snapshot, &mut entry) };
+    }
+
+    Ok(())
+}
+
+fn get_group_sizes() -> Vec<u32> {
+    let group_count = unsafe { GetActiveProcessorGroupCount() };
+    (0..group_count).map(|group| unsafe { GetActiveProcessorCount(group) }).collect()
+}
+
+// Core indices are flat across all processor groups, so with two groups of 48 cores, core 48 is the first core of
+// group 1. Returns one mask per group.
+fn get_group_masks(cores: &[u32], group_sizes: &[u32]) -> Vec<usize> {
+    let mut masks = vec![0usize; group_sizes.len()];
+    'cores: for &core in cores {
+        let mut index = core;
+        for (group, &size) in group_sizes.iter().enumerate() {
+            if index < size {
+                masks[group] |= 1 << index;
+                continue 'cores;
+            }
+            index -= size;
+        }
+
+        log!("Core {core} is out of range for the processor groups on this system. Skipping.");
+    }
+
+    masks
+}
+
+// SetProcessAffinityMask can only target the group the process is in, so on systems with more than one processor
+// group, the include list is applied to each thread with SetThreadGroupAffinity instead. A thread can only be in one
+// group, so all the included cores have to be in the same group. Returns the number of threads that were updated.
+fn set_group_affinity(group_sizes: &[u32], include: &[u32], exclude: &[u32]) -> std::io::Result<usize> {
+    let include_masks = get_group_masks(include, group_sizes);
+    let exclude_masks = get_group_masks(exclude, group_sizes);
+    let targets: Vec<GROUP_AFFINITY> = include_masks
+        .iter()
+        .zip(exclude_masks)
+        .enumerate()
+        .filter(|(_, (include, exclude))| *include & !exclude != 0)
+        .map(|(group, (include, exclude))| GROUP_AFFINITY {
+            Mask: include & !exclude,
+            Group: group as u16,
+            Reserved: [0; 3],
+        })
+        .collect();
+
+    let target = match targets.as_slice() {
+        [target] => *target,
+        [] => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Include and exclude lists leave no cores to run on.",
+            ))
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Included cores span {} processor groups. All included cores must be in the same group.",
+                    targets.len()
+                ),
+            ))
+        }
+    };
+
+    for_each_thread(|thread_handle| unsafe {
+        SetThreadGroupAffinity(thread_handle, &target, std::ptr::null_mut())
+    })
+}
+
+fn get_numa_node_cores(numa_node: u32) -> std::io::Result<Vec<u32>> {
+    let mut highest_node = 0;
+    if !unsafe { GetNumaHighestNodeNumber(&mut highest_node) } {
+        return Err(Error::other(format!(
+            "Unable to get highest NUMA node. Last Error: {}",
+            format_last_error(unsafe { GetLastError() })
+        )));
+    }
+    if numa_node > highest_node {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("NUMA node {numa_node} doesn't exist. This system has NUMA nodes 0-{highest_node}."),
+        ));
+    }
+
+    let mut group_affinity = GROUP_AFFINITY { Mask: 0, Group: 0, Reserved: [0; 3] };
+    if !unsafe { GetNumaNodeProcessorMaskEx(numa_node as u16, &mut group_affinity) } {
+        return Err(Error::other(format!(
+            "Unable to get processors for NUMA node {numa_node}. Last Error: {}",
+            format_last_error(unsafe { GetLastError() })
+        )));
+    }
+
+    Ok(get_group_affinity_cores(&group_affinity, &get_group_sizes()))
+}
+
+// The flat core indices, across all processor groups, of the cores in a group affinity. The inverse of
+// get_group_masks.
+fn get_group_affinity_cores(group_affinity: &GROUP_AFFINITY, group_sizes: &[u32]) -> Vec<u32> {
+    let offset: u32 = group_sizes.iter().take(group_affinity.Group as usize).sum();
+    mask_to_core_list(group_affinity.Mask).into_iter().map(|core| offset + core).collect()
+}
+
+// A physical core, and the logical processors (SMT siblings) that run on it.
+struct ProcessorCore {
+    efficiency_class: u8,
+    processors: Vec<u32>,
+}
+
+fn get_processor_cores() -> std::io::Result<Vec<ProcessorCore>> {
+    let buffer = get_logical_processor_information(RELATION_PROCESSOR_CORE)?;
+    Ok(parse_processor_cores(&buffer, &get_group_sizes()))
+}
+
+// The SYSTEM_LOGICAL_PROCESSOR_INFORMATION_EX records for a relationship, as returned by
+// GetLogicalProcessorInformationEx.
+fn get_logical_processor_information(relationship: u32) -> std::io::Result<Vec<u8>> {
+    let mut length = 0;
+    if !unsafe { GetLogicalProcessorInformationEx(relationship, std::ptr::null_mut(), &mut length) }
+        && unsafe { GetLastError() } != ERROR_INSUFFICIENT_BUFFER
+    {
+        return Err(Error::other(format!(
+            "Unable to get processor information size. Last Error: {}",
+            format_last_error(unsafe { GetLastError() })
+        )));
+    }
+
+    let mut buffer = vec![0u8; length as usize];
+    if !unsafe { GetLogicalProcessorInformationEx(relationship, buffer.as_mut_ptr(), &mut length) } {
+        return Err(Error::other(format!(
+            "Unable to get processor information. Last Error: {}",
+            format_last_error(unsafe { GetLastError() })
+        )));
+    }
+    buffer.truncate(length as usize);
+
+    Ok(buffer)
+}
+
+// Splits the buffer returned by GetLogicalProcessorInformationEx into its records, keeping the ones for relationship.
+fn get_processor_records(buffer: &[u8], relationship: u32) -> Vec<&[u8]> {
+    let mut records = vec![];
+    let mut offset = 0;
+    while offset + 8 <= buffer.len() {
+        let record_relationship = u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap());
+        let size = u32::from_le_bytes(buffer[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        if size == 0 || offset + size > buffer.len() {
+            break;
+        }
+
+        if record_relationship == relationship && size >= PROCESSOR_GROUP_MASK_OFFSET {
+            records.push(&buffer[offset..offset + size]);
+        }
+        offset += size;
+    }
+
+    records
+}
+
+// Cores and packages both use PROCESSOR_RELATIONSHIP. After Relationship and Size, it has Flags, EfficiencyClass,
+// Reserved[20], GroupCount and the GroupMask array.
+const PROCESSOR_EFFICIENCY_CLASS_OFFSET: usize = 9;
+const PROCESSOR_GROUP_COUNT_OFFSET: usize = 30;
+const PROCESSOR_GROUP_MASK_OFFSET: usize = 32;
+
+// The GroupMask array of a PROCESSOR_RELATIONSHIP record. A package can span more than one processor group.
+fn get_record_group_masks(record: &[u8]) -> Vec<GROUP_AFFINITY> {
+    const MASK_SIZE: usize = std::mem::size_of::<usize>();
+    const GROUP_AFFINITY_SIZE: usize = std::mem::size_of::<GROUP_AFFINITY>();
+
+    let group_count = u16::from_le_bytes([
+        record[PROCESSOR_GROUP_COUNT_OFFSET],
+        record[PROCESSOR_GROUP_COUNT_OFFSET + 1],
+    ]) as usize;
+    record[PROCESSOR_GROUP_MASK_OFFSET..]
+        .chunks_exact(GROUP_AFFINITY_SIZE)
+        .take(group_count)
+        .map(|group_mask| GROUP_AFFINITY {
+            Mask: usize::from_le_bytes(group_mask[..MASK_SIZE].try_into().unwrap()),
+            Group: u16::from_le_bytes([group_mask[MASK_SIZE], group_mask[MASK_SIZE + 1]]),
+            Reserved: [0; 3],
+        })
+        .collect()
+}
+
+// Parses the records returned by GetLogicalProcessorInformationEx with RelationProcessorCore. Logical processors are
+// numbered the same way as in get_group_masks.
+fn parse_processor_cores(buffer: &[u8], group_sizes: &[u32]) -> Vec<ProcessorCore> {
+    get_processor_records(buffer, RELATION_PROCESSOR_CORE)
+        .into_iter()
+        .map(|record| ProcessorCore {
+            efficiency_class: record[PROCESSOR_EFFICIENCY_CLASS_OFFSET],
+            processors: get_record_group_masks(record)
+                .iter()
+                .flat_map(|group_mask| get_group_affinity_cores(group_mask, group_sizes))
+                .collect(),
+        })
+        .collect()
+}
+
+// The processors of each package (socket) in the records returned by GetLogicalProcessorInformationEx with
+// RelationProcessorPackage, one entry per processor group the package has processors in. Packages are numbered in
+// the order Windows lists them.
+fn package_masks(buffer: &[u8]) -> Vec<(u32, GROUP_AFFINITY)> {
+    get_processor_records(buffer, RELATION_PROCESSOR_PACKAGE)
+        .into_iter()
+        .enumerate()
+        .flat_map(|(package, record)| {
+            get_record_group_masks(record).into_iter().map(move |group_mask| (package as u32, group_mask))
+        })
+        .collect()
+}
+
+// The flat core indices, across all processor groups, of every processor in the packages.
+fn get_package_cores(packages: &[u32]) -> std::io::Result<Vec<u32>> {
+    let masks = package_masks(&get_logical_processor_information(RELATION_PROCESSOR_PACKAGE)?);
+    let package_count = masks.last().map_or(0, |(package, _)| package + 1);
+    if let Some(package) = packages.iter().find(|&&package| package >= package_count) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Package {package} doesn't exist. This system has packages 0-{}.",
+                package_count.saturating_sub(1)
+            ),
+        ));
+    }
+
+    let group_sizes = get_group_sizes();
+    Ok(masks
+        .iter()
+        .filter(|(package, _)| packages.contains(package))
+        .flat_map(|(_, group_mask)| get_group_affinity_cores(group_mask, &group_sizes))
+        .collect())
+}
+
+// On hybrid CPUs, the efficiency cores have a lower efficiency class than the performance cores. On other CPUs, every
+// core has the same efficiency class, so there are no efficiency cores.
+fn get_efficiency_cores(cores: &[ProcessorCore]) -> Vec<u32> {
+    let Some(performance_class) = cores.iter().map(|core| core.efficiency_class).max() else {
+        return vec![];
+    };
+
+    cores
+        .iter()
+        .filter(|core| core.efficiency_class < performance_class)
+        .flat_map(|core| core.processors.iter().copied())
+        .collect()
+}
+
+fn enumerate_efficiency_cores() -> Vec<u32> {
+    match get_processor_cores() {
+        Ok(cores) => get_efficiency_cores(&cores),
+        Err(e) => {
+            log!("Could not find efficiency cores: {e}");
+            vec![]
+        }
+    }
+}
+
+/// Prints the processor groups, the system affinity mask, and which logical processors share each physical core.
+pub fn print_topology() {
+    let group_sizes = get_group_sizes();
+    log!("Logical processors: {}", group_sizes.iter().sum::<u32>());
+    log!("Processor groups: {} {group_sizes:?}", group_sizes.len());
+
+    let mut process_affinity_mask = 0;
+    let mut system_affinity_mask = 0;
+    if unsafe { GetProcessAffinityMask(self_process_handle(), &mut process_affinity_mask, &mut system_affinity_mask) } {
+        log!("System affinity mask:\n{}", format_mask(system_affinity_mask, current_group_processor_count()));
+    } else {
+        log!("Unable to get system affinity mask. Last Error: {}", format_last_error(unsafe { GetLastError() }));
+    }
+
+    let cores = match get_processor_cores() {
+        Ok(cores) => cores,
+        Err(e) => {
+            log!("Could not get processor cores: {e}");
+            return;
+        }
+    };
+    let performance_class = cores.iter().map(|core| core.efficiency_class).max().unwrap_or_default();
+    for (index, core) in cores.iter().enumerate() {
+        log!("Core {index}: {}", describe_core(core, performance_class));
+    }
+}
+
+fn describe_core(core: &ProcessorCore, performance_class: u8) -> String {
+    let processors = core.processors.iter().map(u32::to_string).collect::<Vec<_>>().join(", ");
+    let siblings = if core.processors.len() > 1 { " (SMT siblings)" } else { "" };
+    let kind = if core.efficiency_class < performance_class { "efficiency" } else { "performance" };
+    format!("processors {processors}{siblings}, efficiency class {} ({kind})", core.efficiency_class)
+}
+
+/// The affinity state of the current process, read all at once so the masks agree with each other.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AffinitySnapshot {
+    pub process_affinity_mask: usize,
+    pub system_affinity_mask: usize,
+    /// Logical processors across every processor group.
+    pub processor_count: u32,
+    /// Logical processors in the process's processor group, which both masks are a view of.
+    pub group_processor_count: u32,
+    /// Cores set in `process_affinity_mask`.
+    pub enabled_cores: Vec<u32>,
+}
+
+impl AffinitySnapshot {
+    fn new(
+        process_affinity_mask: usize,
+        system_affinity_mask: usize,
+        processor_count: u32,
+        group_processor_count: u32,
+    ) -> Self {
+        Self {
+            process_affinity_mask,
+            system_affinity_mask,
+            processor_count,
+            group_processor_count,
+            enabled_cores: mask_to_core_list(process_affinity_mask),
+        }
+    }
+}
+
+impl Display for AffinitySnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let cores = self.enabled_cores.iter().map(u32::to_string).collect::<Vec<_>>().join(", ");
+        let group_count = self.group_processor_count;
+        writeln!(f, "Process affinity mask:\n{}", format_mask(self.process_affinity_mask, group_count))?;
+        writeln!(f, "System affinity mask:\n{}", format_mask(self.system_affinity_mask, group_count))?;
+        writeln!(f, "Logical processors: {}", self.processor_count)?;
+        if group_count != self.processor_count {
+            writeln!(f, "Logical processors in this group: {group_count}")?;
+        }
+        write!(f, "Enabled cores: {cores}")
+    }
+}
+
+/// Returns the process and system affinity masks of the current process, along with the logical processor count and
+/// the cores the process can run on.
+pub fn affinity_snapshot() -> Result<AffinitySnapshot, AffinityError> {
+    let mut process_affinity_mask = 0;
+    let mut system_affinity_mask = 0;
+    if !unsafe { GetProcessAffinityMask(self_process_handle(), &mut process_affinity_mask, &mut system_affinity_mask) } {
+        return Err(AffinityError::QueryFailed(unsafe { GetLastError() }));
+    }
+
+    Ok(AffinitySnapshot::new(
+        process_affinity_mask,
+        system_affinity_mask,
+        total_logical_processors(),
+        current_group_processor_count(),
+    ))
+}
+
+fn get_priority_class(priority: &str) -> std::io::Result<u32> {
+    match priority {
+        "high" => Ok(HIGH_PRIORITY_CLASS),
+        "above_normal" => Ok(ABOVE_NORMAL_PRIORITY_CLASS),
+        "normal" => Ok(NORMAL_PRIORITY_CLASS),
+        "below_normal" => Ok(BELOW_NORMAL_PRIORITY_CLASS),
+        "idle" => Ok(IDLE_PRIORITY_CLASS),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unknown priority \"{priority}\". Expected one of: high, above_normal, normal, below_normal, idle"),
+        )),
+    }
+}
+
+fn get_thread_priority(thread_priority: &str) -> std::io::Result<i32> {
+    match thread_priority {
+        "time_critical" => Ok(THREAD_PRIORITY_TIME_CRITICAL),
+        "highest" => Ok(THREAD_PRIORITY_HIGHEST),
+        "above_normal" => Ok(THREAD_PRIORITY_ABOVE_NORMAL),
+        "normal" => Ok(THREAD_PRIORITY_NORMAL),
+        "below_normal" => Ok(THREAD_PRIORITY_BELOW_NORMAL),
+        "lowest" => Ok(THREAD_PRIORITY_LOWEST),
+        "idle" => Ok(THREAD_PRIORITY_IDLE),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Unknown thread priority \"{thread_priority}\". Expected one of: time_critical, highest, above_normal, \
+                normal, below_normal, lowest, idle"
+            ),
+        )),
+    }
+}
+
+fn set_priority_class(priority: u32) -> std::io::Result<()> {
+    let process_handle = self_process_handle();
+
+    let current_priority = unsafe { GetPriorityClass(process_handle) };
+    if current_priority == 0 {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Unable to get process priority class. Last Error: {}",
+                format_last_error(unsafe { GetLastError() })
+            ),
+        ));
+    }
+
+    if current_priority == priority {
+        return Ok(());
+    }
+
+    if !unsafe { SetPriorityClass(process_handle, priority) } {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Unable to set process priority class. Last Error: {}",
+                format_last_error(unsafe { GetLastError() })
+            ),
+        ));
+    }
+
+    log!("Priority class before: {current_priority:X} after: {priority:X}");
+
+    Ok(())
+}
+
+// Looks up a kernel32 function that isn't on every version of Windows. Returns null if it doesn't exist.
+fn get_kernel32_function(name: &std::ffi::CStr) -> *const std::ffi::c_void {
+    get_module_function("kernel32.dll", name)
+}
+
+// Null if the module isn't loaded or doesn't export the function.
+fn get_module_function(module: &str, name: &std::ffi::CStr) -> *const std::ffi::c_void {
+    let module: Vec<u16> = module.encode_utf16().chain(std::iter::once(0)).collect();
+    let module_handle = unsafe { GetModuleHandleW(module.as_ptr()) };
+    if module_handle == 0 {
+        return std::ptr::null();
+    }
+    unsafe { GetProcAddress(module_handle, name.as_ptr()) }
+}
+
+/// Whether the process is running under Wine or Proton, which export `wine_get_version` from ntdll.
+pub fn is_wine() -> bool {
+    !get_module_function("ntdll.dll", c"wine_get_version").is_null()
+}
+
+// Enabling throttles the execution speed of the process (EcoQoS) to save power. Disabling it explicitly opts out, so
+// Windows doesn't decide to throttle it on its own.
+fn set_power_throttling(enabled: bool) -> std::io::Result<()> {
+    let set_process_information = get_kernel32_function(c"SetProcessInformation");
+    if set_process_information.is_null() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "SetProcessInformation isn't available on this version of Windows",
+        ));
+    }
+    let set_process_information: SetProcessInformationFn = unsafe { std::mem::transmute(set_process_information) };
+
+    let state = PROCESS_POWER_THROTTLING_STATE {
+        Version: PROCESS_POWER_THROTTLING_CURRENT_VERSION,
+        ControlMask: PROCESS_POWER_THROTTLING_EXECUTION_SPEED,
+        StateMask: if enabled { PROCESS_POWER_THROTTLING_EXECUTION_SPEED } else { 0 },
+    };
+    // Windows 8 has SetProcessInformation, but not the power throttling class, so this fails there.
+    if !unsafe {
+        set_process_information(
+            self_process_handle(),
+            PROCESS_POWER_THROTTLING,
+            &state as *const _ as *const std::ffi::c_void,
+            std::mem::size_of::<PROCESS_POWER_THROTTLING_STATE>() as u32,
+        )
+    } {
+        return Err(Error::other(format!(
+            "Unable to set power throttling. Last Error: {}",
+            format_last_error(unsafe { GetLastError() })
+        )));
+    }
+
+    Ok(())
+}
+
+fn restore_processor_affinity() -> Result<(), AffinityError> {
+    // Passing no CPU Sets clears them, so the process can run on every core in its affinity mask again.
+    if CPU_SETS_APPLIED.swap(false, Ordering::SeqCst) {
+        if let Some(set_process_default_cpu_sets) = get_set_cpu_sets_function() {
+            if !unsafe { set_process_default_cpu_sets(self_process_handle(), std::ptr::null(), 0) } {
+                return Err(AffinityError::SetFailed {
+                    requested: 0,
+                    last_error: unsafe { GetLastError() },
+                });
+            }
+        }
+    }
+
+    let original_mask = ORIGINAL_AFFINITY_MASK.swap(0, Ordering::SeqCst);
+    if original_mask == 0 {
+        return Ok(());
+    }
+
+    let process_handle = self_process_handle();
+    if !unsafe { SetProcessAffinityMask(process_handle, original_mask) } {
+        return Err(AffinityError::SetFailed {
+            requested: original_mask,
+            last_error: unsafe { GetLastError() },
+        });
+    }
+
+    Ok(())
+}
+
+
+#[test]
+fn test_reload_config_removed() {
+    let config_path = std::env::temp_dir().join("affinity-removed-for-test.toml");
+    let error = reload_config(&config_path).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::NotFound);
+    assert!(error.to_string().contains("was removed"), "{error}");
+}
+
+
+#[test]
+fn test_toml() {
+    let toml = fs::read_to_string(CONFIG_PATH).unwrap();
+    let hinstDLL = unsafe { GetModuleHandleA(0 as _) };
+    let config_path = get_config_path(hinstDLL).unwrap();
+    fs::write(&config_path, toml).unwrap();
+
+    let config = read_config_file(&config_path, &SystemInfo::query()).expect("Could not read config");
+
+    println!("{:?} {:?}", config.delay, config.exclude)
+}
+
+
+#[test]
+fn test_json_config() {
+    let json = r#"{ "delay": "500ms", "exclude": ["0-1", 4], "priority": "high", "per_thread": true }"#;
+    let config: Config = parse_config(Path::new(JSON_CONFIG_PATH), json).unwrap();
+    assert_eq!(config.delay, Duration::from_millis(500));
+    assert_eq!(config.exclude, vec![0, 1, 4]);
+    assert_eq!(config.priority.as_deref(), Some("high"));
+    assert!(config.per_thread);
+
+    let error = parse_config::<Config>(Path::new(JSON_CONFIG_PATH), r#"{ "delay": 5, "exclude": [0, "#).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+}
+
+
+#[test]
+fn test_get_config_path_override() {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+    assert_eq!(get_config_path_override(Some(manifest.clone().into_os_string())), Some(manifest));
+    assert_eq!(get_config_path_override(Some("does/not/exist.toml".into())), None);
+    assert_eq!(get_config_path_override(None), None);
+}
+
+
+#[test]
+fn test_get_config_base() {
+    let config_path = Path::new("C:/Games/Game/affinity.toml");
+    assert_eq!(get_config_base(config_path, ConfigBase::Dll).unwrap(), Path::new("C:/Games/Game"));
+    assert_eq!(get_config_base(Path::new("affinity.toml"), ConfigBase::Dll).unwrap(), Path::new(""));
+    assert_eq!(get_config_base(config_path, ConfigBase::Cwd).unwrap(), std::env::current_dir().unwrap());
+}
+
+
+#[test]
+fn test_parse_core_spec() {
+    assert_eq!(parse_core_spec("8").unwrap(), vec![8]);
+    assert_eq!(parse_core_spec(" 12 ").unwrap(), vec![12]);
+    assert_eq!(parse_core_spec("0-3").unwrap(), vec![0, 1, 2, 3]);
+    assert_eq!(parse_core_spec("12 - 15").unwrap(), vec![12, 13, 14, 15]);
+    assert_eq!(parse_core_spec("5-5").unwrap(), vec![5]);
+}
+
+#[test]
+fn test_normalize_cores() {
+    assert_eq!(normalize_cores(&[3, 0, 1]), (vec![0, 1, 3], vec![]));
+    assert_eq!(normalize_cores(&[2, 0, 2, 1, 2, 0]), (vec![0, 1, 2], vec![0, 2]));
+    assert_eq!(normalize_cores(&[]), (vec![], vec![]));
+    assert_eq!(normalize_exclude(&[8, 9, 8]), vec![8, 9]);
+}
+
+
+#[test]
+fn test_parse_core_spec_malformed() {
+    assert!(parse_core_spec("").is_err());
+    assert!(parse_core_spec("5-").is_err());
+    assert!(parse_core_spec("-5").is_err());
+    assert!(parse_core_spec("3-1").is_err());
+    assert!(parse_core_spec("a-b").is_err());
+    assert!(parse_core_spec("1-2-3").is_err());
+    assert!(parse_core_spec("potato").is_err());
+}
+
+#[test]
+fn test_parse_core_spec_last() {
+    let resolve = |specs: &[&str], processor_count| -> Vec<u32> {
+        specs.iter().flat_map(|spec| parse_core_spec_with(spec, processor_count).unwrap()).collect()
+    };
+    assert_eq!(resolve(&["last", "last-1"], 8), vec![7, 6]);
+    assert_eq!(resolve(&["last", "last-1"], 16), vec![15, 14]);
+    assert_eq!(resolve(&["last - 2", "4-last"], 8), vec![5, 4, 5, 6, 7]);
+    assert_eq!(resolve(&["0-last-6"], 8), vec![0, 1]);
+    // Exclude resolves against the current group, so two groups of 48 leave out 46 and 47, not 94 and 95.
+    assert_eq!(resolve(&["last", "last-1"], 48), vec![47, 46]);
+
+    assert!(parse_core_spec_with("last-8", 8).is_err());
+    assert!(parse_core_spec_with("last", 0).is_err());
+    assert!(parse_core_spec_with("last+1", 8).is_err());
+    assert!(parse_core_spec_with("last-a", 8).is_err());
+    assert!(parse_core_spec_with("last-1-last", 8).is_err());
+}
+
+#[test]
+fn test_core_mask_out_of_range() {
+    assert_eq!(get_exclude_mask(&[64, 100]), 0);
+    assert_eq!(get_exclude_mask(&[0, 64, 3, 100]), 0b1001);
+    assert_eq!(get_include_mask(&[63]), 1 << 63);
+}
+
+#[test]
+fn test_describe_core() {
+    let performance = ProcessorCore { efficiency_class: 1, processors: vec![0, 1] };
+    let efficiency = ProcessorCore { efficiency_class: 0, processors: vec![16] };
+    assert_eq!(describe_core(&performance, 1), "processors 0, 1 (SMT siblings), efficiency class 1 (performance)");
+    assert_eq!(describe_core(&efficiency, 1), "processors 16, efficiency class 0 (efficiency)");
+}
+
+
+#[test]
+fn test_check_ideal_processor() {
+    assert!(check_ideal_processor(2, &[0, 1], 8).is_ok());
+    assert!(check_ideal_processor(7, &[], 8).is_ok());
+    assert!(matches!(check_ideal_processor(8, &[], 8), Err(AffinityError::InvalidConfig(_))));
+    assert!(matches!(check_ideal_processor(1, &[0, 1], 8), Err(AffinityError::InvalidConfig(_))));
+}
+
+
+#[test]
+fn test_get_percent_exclude() {
+    assert_eq!(get_percent_exclude(25.0, 4), vec![0]);
+    assert_eq!(get_percent_exclude(25.0, 8), vec![0, 1]);
+    assert_eq!(get_percent_exclude(25.0, 16), vec![0, 1, 2, 3]);
+    assert_eq!(get_percent_exclude(30.0, 4), vec![0, 1]);
+    assert_eq!(get_percent_exclude(0.0, 8), vec![]);
+    assert_eq!(get_percent_exclude(100.0, 4), vec![0, 1, 2]);
+    assert_eq!(get_percent_exclude(50.0, 1), vec![]);
+
+    // With two groups of 64, a quarter of the current group is 16 cores, not 32.
+    let dual_group = SystemInfo { processor_count: 128, group_processor_count: 64, system_affinity_mask: usize::MAX };
+    let config = Config { exclude_percent: Some(25.0), ..Config::default() };
+    assert_eq!(get_counted_exclude(&config, &dual_group), (0..16).collect::<Vec<_>>());
+}
+
+
+#[test]
+fn test_format_benchmark() {
+    assert_eq!(
+        format_benchmark(&[(0, 400.0), (1, 800.0), (2, 600.0)]),
+        "Core benchmark, fastest first:\n  Core  1:    800.0 M/s 100.0%\n  Core  2:    600.0 M/s  75.0%\n  \
+         Core  0:    400.0 M/s  50.0%"
+    );
+    assert_eq!(format_benchmark(&[]), "Core benchmark, fastest first:");
+}
+
+
+#[test]
+fn test_spread_indices() {
+    assert_eq!(spread_indices(4, 16), vec![0, 4, 8, 12]);
+    assert_eq!(spread_indices(3, 8), vec![0, 2, 5]);
+    assert_eq!(spread_indices(1, 8), vec![0]);
+    assert_eq!(spread_indices(0, 8), vec![]);
+    assert_eq!(spread_indices(8, 8), vec![0, 1, 2, 3, 4, 5, 6, 7]);
+    assert_eq!(spread_indices(10, 4), vec![0, 1, 2, 3]);
+    assert_eq!(spread_indices(2, 0), vec![]);
+
+    assert_eq!(get_balance_exclude(4, 16), vec![0, 4, 8, 12]);
+    assert_eq!(get_balance_exclude(4, 4), vec![0, 1, 2]);
+    assert_eq!(get_balance_exclude(2, 1), vec![]);
+
+    // With two groups of 64, the cores are spread over the current group, so they all fit in its mask.
+    let dual_group = SystemInfo { processor_count: 128, group_processor_count: 64, system_affinity_mask: usize::MAX };
+    let config = Config { balance_exclude: Some(4), ..Config::default() };
+    assert_eq!(get_counted_exclude(&config, &dual_group), vec![0, 16, 32, 48]);
+}
+
+
+// Applies masks to a fake process, limited to the cores in `applied`, without touching the real affinity.
+#[cfg(test)]
+struct FakeAffinity {
+    process: std::cell::Cell<usize>,
+    system: usize,
+    applied: usize,
+    // The number of sets that get undone, like a game changing its own affinity right after.
+    undone: std::cell::Cell<u32>,
+}
+
+#[cfg(test)]
+impl FakeAffinity {
+    fn new(process: usize, system: usize) -> Self {
+        FakeAffinity {
+            process: std::cell::Cell::new(process),
+            system,
+            applied: usize::MAX,
+            undone: std::cell::Cell::new(0),
+        }
+    }
+}
+
+#[cfg(test)]
+impl AffinityApi for FakeAffinity {
+    fn get_mask(&self) -> Result<(usize, usize), AffinityError> {
+        Ok((self.process.get(), self.system))
+    }
+
+    fn set_mask(&self, mask: usize) -> Result<(), AffinityError> {
+        if self.undone.get() > 0 {
+            self.undone.set(self.undone.get() - 1);
+            return Ok(());
+        }
+
+        self.process.set(mask & self.applied);
+        Ok(())
+    }
+}
+
+
+#[test]
+fn test_get_missing_ordinal_exports() {
+    // Like xinput1_3, which doesn't have 104 or 108, behind a proxy that only forwards XInputGetStateEx.
+    let real_exports = |ordinal| (100..=103).contains(&ordinal);
+    let missing = get_missing_ordinal_exports(&XINPUT_ORDINAL_EXPORTS, real_exports, |ordinal| ordinal == 100);
+    assert_eq!(
+        missing,
+        ["XInputWaitForGuideButton (101)", "XInputCancelGuideButtonWait (102)", "XInputPowerOffController (103)"]
+    );
+    assert!(get_missing_ordinal_exports(&XINPUT_ORDINAL_EXPORTS, real_exports, real_exports).is_empty());
+}
+
+
+#[test]
+fn test_affinity_outcome_mask() {
+    assert_eq!(AffinityOutcome::Applied(Some(0b1100)).mask(), Some(0b1100));
+    assert_eq!(AffinityOutcome::Applied(None).mask(), None);
+    assert_eq!(AffinityOutcome::Unchanged(Some(0b11)).mask(), Some(0b11));
+    assert_eq!(AffinityOutcome::Failed.mask(), None);
+}
+
+
+#[test]
+fn test_take_control_pipe_mask() {
+    assert_eq!(take_control_pipe_mask(), None);
+    set_control_pipe_mask(0b1100);
+    assert_eq!(take_control_pipe_mask(), Some(0b1100));
+    // It is only taken once, so the reapply loop doesn't keep going back to it.
+    assert_eq!(take_control_pipe_mask(), None);
+}
+
+
+#[test]
+fn test_encode_affinity_result() {
+    assert_eq!(encode_affinity_result(RESULT_PENDING, 0), 0);
+    assert_eq!(encode_affinity_result(RESULT_APPLIED, 0), 1 << 56);
+    assert_eq!(encode_affinity_result(RESULT_FAILED, 5), 3 << 56 | 5);
+    assert_eq!(get_error_code(&AffinityError::SetFailed { requested: 1, last_error: 87 }), 87);
+    assert_eq!(get_error_code(&AffinityError::SingleCpu), 0);
+    let error = AffinityError::ConstrainedByJob { requested: 1, job_affinity: None };
+    assert_eq!(get_error_code(&error), ERROR_ACCESS_DENIED as u64);
+}
+
+
+#[test]
+fn test_last_affinity_mask() {
+    set_last_affinity_applied(0b1110);
+    assert_eq!(get_last_affinity_result(), RESULT_APPLIED << RESULT_STATUS_SHIFT | 0b1110);
+    assert_eq!(get_last_affinity_mask(), 0b1110);
+    // Every core of a 64 core group is kept in the mask, and the result only has room for the first 56.
+    set_last_affinity_applied(usize::MAX);
+    assert_eq!(get_last_affinity_result(), RESULT_APPLIED << RESULT_STATUS_SHIFT | ((1 << RESULT_STATUS_SHIFT) - 1));
+    assert_eq!(get_last_affinity_mask(), usize::MAX);
+    set_last_affinity_result(RESULT_FAILED, 5);
+    assert_eq!(get_last_affinity_result() >> RESULT_STATUS_SHIFT, RESULT_FAILED);
+    assert_eq!(get_last_affinity_mask(), 0);
+}
+
+
+#[test]
+fn test_constrained_by_job() {
+    let error = AffinityError::ConstrainedByJob { requested: 0b1110, job_affinity: Some(0b0011) };
+    let message = error.to_string();
+    assert!(message.contains("job object"));
+    assert!(message.ends_with(&format!("Job: {}", describe_mask(0b0011))));
+    let message = AffinityError::ConstrainedByJob { requested: 0b1110, job_affinity: None }.to_string();
+    assert!(!message.contains("Job:"));
+    assert!(message.contains("can limit"));
+    assert_eq!(Error::from(error).kind(), ErrorKind::PermissionDenied);
+}
+
+
+#[test]
+fn test_apply_affinity_toml_disabled() {
+    let toml = "enabled = false\nexclude = [0]";
+    assert_eq!(unsafe { apply_affinity_toml(toml.as_ptr(), toml.len()) }, APPLY_TOML_UNCHANGED);
+}
+
+
+#[test]
+fn test_apply_affinity_toml_invalid() {
+    assert_eq!(unsafe { apply_affinity_toml(std::ptr::null(), 0) }, APPLY_TOML_INVALID_POINTER);
+    let bytes = [0xff, 0xfe];
+    assert_eq!(unsafe { apply_affinity_toml(bytes.as_ptr(), bytes.len()) }, APPLY_TOML_INVALID_UTF8);
+    let toml = "exclude = ";
+    assert_eq!(unsafe { apply_affinity_toml(toml.as_ptr(), toml.len()) }, APPLY_TOML_PARSE_FAILED);
+}
+
+#[test]
+fn test_affinity_scope() {
+    let api = FakeAffinity::new(0b1111, 0b1111);
+    {
+        let scope = AffinityScope::apply_with(&api, 0b0110).unwrap();
+        assert_eq!(scope.previous_mask(), Some(0b1111));
+        assert_eq!(api.process.get(), 0b0110);
+    }
+    assert_eq!(api.process.get(), 0b1111);
+
+    // A forgotten scope keeps the new mask.
+    AffinityScope::apply_with(&api, 0b0011).unwrap().forget();
+    assert_eq!(api.process.get(), 0b0011);
+}
+
+
+#[test]
+fn test_set_processor_affinity_exclude() {
+    let api = FakeAffinity::new(0b1111, 0b1111);
+    let options = AffinityOptions::default();
+    assert_eq!(set_processor_affinity_with(&api, None, 0b0011, &options).unwrap(), 0b1100);
+    assert_eq!(api.process.get(), 0b1100);
+
+    // CPU 0 is now off, so excluding again leaves the affinity alone.
+    assert!(matches!(
+        set_processor_affinity_with(&api, None, 0b0100, &options),
+        Err(AffinityError::CpuZeroAlreadyOff)
+    ));
+    assert_eq!(api.process.get(), 0b1100);
+
+    let api = FakeAffinity::new(0b0001, 0b0001);
+    assert!(matches!(set_processor_affinity_with(&api, None, 0b0001, &options), Err(AffinityError::SingleCpu)));
+    assert_eq!(api.process.get(), 0b0001);
+}
+
+
+#[test]
+fn test_set_processor_affinity_cpu0_off() {
+    let api = FakeAffinity::new(0b1110, 0b1111);
+    let options = AffinityOptions { only_if_cpu0_enabled: false, ..AffinityOptions::default() };
+    assert_eq!(set_processor_affinity_with(&api, None, 0b1000, &options).unwrap(), 0b0110);
+    assert_eq!(api.process.get(), 0b0110);
+}
+
+
+#[test]
+fn test_set_processor_affinity_respect_existing() {
+    let options = AffinityOptions { respect_existing: true, ..AffinityOptions::default() };
+    // Cores 2-5 are already inside everything but core 0, so they are kept.
+    let api = FakeAffinity::new(0b11_1100, 0xFF);
+    assert!(matches!(
+        set_processor_affinity_with(&api, None, 0b1, &options),
+        Err(AffinityError::AlreadyNarrower { process: 0b11_1100, requested: 0b1111_1110 })
+    ));
+    assert_eq!(api.process.get(), 0b11_1100);
+
+    // A mask that isn't inside the config's cores is still changed.
+    assert_eq!(set_processor_affinity_with(&api, Some(0xF0), 0, &options).unwrap(), 0xF0);
+    let api = FakeAffinity::new(0b1111, 0xFF);
+    assert_eq!(set_processor_affinity_with(&api, None, 0b100, &options).unwrap(), 0b1011);
+
+    // Without the option, include widens the affinity again.
+    let api = FakeAffinity::new(0b11_1100, 0xFF);
+    let options = AffinityOptions::default();
+    assert_eq!(set_processor_affinity_with(&api, Some(0xFE), 0, &options).unwrap(), 0xFE);
+}
+
+
+#[test]
+fn test_set_processor_affinity_min_cores() {
+    let options = AffinityOptions { min_cores: Some(2), ..AffinityOptions::default() };
+    let api = FakeAffinity::new(0b1111, 0b1111);
+    assert!(matches!(
+        set_processor_affinity_with(&api, None, 0b0111, &options),
+        Err(AffinityError::TooFewCores { remaining: 1, min_cores: 2 })
+    ));
+    assert_eq!(api.process.get(), 0b1111);
+    assert_eq!(set_processor_affinity_with(&api, None, 0b0011, &options).unwrap(), 0b1100);
+
+    let api = FakeAffinity::new(0b1111_1111, 0b1111_1111);
+    assert_eq!(set_processor_affinity_with(&api, None, 0b0000_1111, &options).unwrap(), 0b1111_0000);
+}
+
+
+#[test]
+fn test_set_processor_affinity_invert() {
+    let options = AffinityOptions { invert: true, ..AffinityOptions::default() };
+    // CPU 0 being off doesn't matter, since nothing is being excluded.
+    let api = FakeAffinity::new(0b1110, 0b1111);
+    assert_eq!(set_processor_affinity_with(&api, None, 0b0110, &options).unwrap(), 0b0110);
+
+    let api = FakeAffinity::new(0b1100, 0b1111);
+    assert!(matches!(
+        set_processor_affinity_with(&api, None, 0b0011, &options),
+        Err(AffinityError::NoCoresLeft { include: 0b0011, exclude: 0, system: 0b1100 })
+    ));
+    assert_eq!(api.process.get(), 0b1100);
+}
+
+
+#[test]
+fn test_set_processor_affinity_retries() {
+    let options = AffinityOptions { retries: 2, ..AffinityOptions::default() };
+    let api = FakeAffinity::new(0b1111, 0b1111);
+    api.undone.set(2);
+    assert_eq!(set_processor_affinity_with(&api, None, 0b0001, &options).unwrap(), 0b1110);
+    assert_eq!(api.process.get(), 0b1110);
+
+    let api = FakeAffinity::new(0b1111, 0b1111);
+    api.undone.set(3);
+    assert!(matches!(
+        set_processor_affinity_with(&api, None, 0b0001, &options),
+        Err(AffinityError::NotApplied { requested: 0b1110, applied: 0b1111 })
+    ));
+
+    // Without retries, the first set that doesn't stick is an error.
+    let api = FakeAffinity::new(0b1111, 0b1111);
+    api.undone.set(1);
+    assert!(set_processor_affinity_with(&api, None, 0b0001, &AffinityOptions::default()).is_err());
+}
+
+
+#[test]
+fn test_set_processor_affinity_no_verify() {
+    // Without reading it back, a set that doesn't stick still counts.
+    let options = AffinityOptions { verify: false, ..AffinityOptions::default() };
+    let api = FakeAffinity::new(0b1111, 0b1111);
+    api.undone.set(1);
+    assert_eq!(set_processor_affinity_with(&api, None, 0b0001, &options).unwrap(), 0b1110);
+    assert_eq!(api.process.get(), 0b1111);
+}
+
+
+#[test]
+fn test_set_processor_affinity_empty_process_mask() {
+    let options = AffinityOptions::default();
+    let api = FakeAffinity::new(0, 0b1111);
+    assert!(matches!(
+        set_processor_affinity_with(&api, None, 0b0001, &options),
+        Err(AffinityError::EmptyProcessMask)
+    ));
+    assert!(matches!(
+        set_processor_affinity_with(&api, Some(0b0110), 0, &options),
+        Err(AffinityError::EmptyProcessMask)
+    ));
+
+    // A process that can only run on one core has nothing to exclude, whichever core it is.
+    let api = FakeAffinity::new(0b0100, 0b1111);
+    assert!(matches!(set_processor_affinity_with(&api, None, 0b0001, &options), Err(AffinityError::SingleCpu)));
+    assert_eq!(api.process.get(), 0b0100);
+}
+
+
+#[test]
+fn test_set_processor_affinity_include() {
+    // Include ignores the current mask, so it works even when CPU 0 is off.
+    let options = AffinityOptions::default();
+    let api = FakeAffinity::new(0b1110, 0b1111);
+    assert_eq!(set_processor_affinity_with(&api, Some(0b10110), 0b0100, &options).unwrap(), 0b0010);
+
+    let api = FakeAffinity::new(0b1111, 0b1111);
+    assert!(matches!(
+        set_processor_affinity_with(&api, Some(0b0011), 0b0011, &options),
+        Err(AffinityError::NoCoresLeft { include: 0b0011, exclude: 0b0011, system: 0b1111 })
+    ));
+}
+
+
+#[test]
+fn test_set_processor_affinity_unavailable_cores() {
+    let options = AffinityOptions::default();
+    // Cores the system doesn't have are left out, and the rest are still set.
+    let api = FakeAffinity::new(0b1111, 0b1111);
+    assert_eq!(set_processor_affinity_with(&api, Some(0b1_1000), 0, &options).unwrap(), 0b1000);
+
+    let api = FakeAffinity::new(0b1111, 0b1111);
+    assert!(matches!(
+        set_processor_affinity_with(&api, Some(0b11_0000), 0, &options),
+        Err(AffinityError::NoAvailableCores { requested: 0b11_0000, system: 0b1111 })
+    ));
+    assert_eq!(api.process.get(), 0b1111);
+}
+
+
+#[test]
+fn test_set_processor_affinity_not_applied() {
+    let api = FakeAffinity { applied: 0b0011, ..FakeAffinity::new(0b1111, 0b1111) };
+    assert!(matches!(
+        set_processor_affinity_with(&api, None, 0b1000, &AffinityOptions::default()),
+        Err(AffinityError::NotApplied { requested: 0b0111, applied: 0b0011 })
+    ));
+}
+
+
+#[test]
+fn test_scale_jitter() {
+    assert_eq!(scale_jitter(2.0, 0), Duration::ZERO);
+    assert_eq!(scale_jitter(2.0, 1 << 63), Duration::from_secs(1));
+    assert!(scale_jitter(2.0, u64::MAX) < Duration::from_secs(2));
+    assert_eq!(scale_jitter(0.0, u64::MAX), Duration::ZERO);
+    assert!(scale_jitter(1e18, u64::MAX) < Duration::from_secs(1e18 as u64));
+}
+
+
+#[test]
+fn test_format_mask() {
+    assert_eq!(format_mask(0b11110011, 8), "0    4\n1100 1111");
+    // Cores past the end of the mask are shown as off, up to the core count.
+    assert_eq!(format_mask(0b11, 6), "0    4\n1100 00");
+    assert_eq!(format_mask(0, 0), "(none)");
+    assert_eq!(format_mask(0b1111, 0), "(none)");
+    // Exactly the core count is shown, so a core past it is left out.
+    assert_eq!(format_mask(1 << 9 | 1, 4), "0\n1000");
+    assert_eq!(format_mask(0b1, 5), "0    4\n1000 0");
+    let bits = usize::BITS as usize;
+    assert_eq!(format_mask(usize::MAX, 128).lines().last().unwrap().len(), bits + bits / 4 - 1);
+    assert_eq!(get_mask_bits(0b111100, 8), "0011 1100");
+    assert_eq!(get_mask_bits(0b111100, 0), "");
+}
+
+
+#[test]
+fn test_mask_to_core_list() {
+    assert_eq!(mask_to_core_list(0), vec![]);
+    assert_eq!(mask_to_core_list(0b1011), vec![0, 1, 3]);
+    assert_eq!(mask_to_core_list(1 << (usize::BITS - 1)), vec![usize::BITS - 1]);
+
+    for mask in [0, 1, 0b1011, 0b111100, usize::MAX, 1 << (usize::BITS - 1)] {
+        assert_eq!(core_list_to_mask(&mask_to_core_list(mask)), mask);
+    }
+    for cores in [vec![], vec![0], vec![2, 3, 4, 5], vec![1, 7, usize::BITS - 1]] {
+        assert_eq!(mask_to_core_list(core_list_to_mask(&cores)), cores);
+    }
+    // Cores that don't fit in the mask are skipped.
+    assert_eq!(core_list_to_mask(&[1, usize::BITS]), 0b10);
+}
+
+
+#[test]
+fn test_get_child_pids() {
+    let processes = [(0, 0), (4, 0), (100, 4), (200, 100), (300, 100), (400, 200)];
+    assert_eq!(get_child_pids(&processes, 100), vec![200, 300]);
+    assert_eq!(get_child_pids(&processes, 0), vec![4]);
+    assert_eq!(get_child_pids(&processes, 400), vec![]);
+}
+
+
+#[test]
+fn test_get_group_affinity_cores() {
+    let node = GROUP_AFFINITY { Mask: 0b1110, Group: 0, Reserved: [0; 3] };
+    assert_eq!(get_group_affinity_cores(&node, &[48, 48]), vec![1, 2, 3]);
+
+    let node = GROUP_AFFINITY { Mask: 0b0011, Group: 1, Reserved: [0; 3] };
+    assert_eq!(get_group_affinity_cores(&node, &[48, 48]), vec![48, 49]);
+    assert_eq!(get_group_masks(&get_group_affinity_cores(&node, &[48, 48]), &[48, 48]), vec![0, 0b0011]);
+}
+
+
+#[test]
+fn test_get_group_target() {
+    let target = get_group_target(1, 48, None, 0b0011).unwrap();
+    assert_eq!((target.Group, target.Mask), (1, ((1 << 48) - 1) & !0b0011));
+    // Include cores past the end of the group are dropped.
+    let target = get_group_target(0, 4, Some(0b110110), 0b0100).unwrap();
+    assert_eq!(target.Mask, 0b0010);
+    assert_eq!(get_group_target(0, 64, None, 0).unwrap().Mask, usize::MAX);
+    assert!(get_group_target(1, 4, Some(0b0011), 0b0011).is_err());
+
+    assert!(check_group(1, 2).is_ok());
+    assert!(check_group(2, 2).is_err());
+}
+
+
+#[test]
+fn test_get_priority_class() {
+    assert_eq!(get_priority_class("high").unwrap(), HIGH_PRIORITY_CLASS);
+    assert_eq!(get_priority_class("above_normal").unwrap(), ABOVE_NORMAL_PRIORITY_CLASS);
+    assert_eq!(get_priority_class("normal").unwrap(), NORMAL_PRIORITY_CLASS);
+    assert_eq!(get_priority_class("below_normal").unwrap(), BELOW_NORMAL_PRIORITY_CLASS);
+    assert_eq!(get_priority_class("idle").unwrap(), IDLE_PRIORITY_CLASS);
+    assert!(get_priority_class("realtime").is_err());
+    assert!(get_priority_class("High").is_err());
+}
+
+#[test]
+fn test_get_group_masks() {
+    assert_eq!(get_group_masks(&[0, 63, 64, 127, 128], &[64, 64]), vec![1 | 1 << 63, 1 | 1 << 63]);
+    assert_eq!(get_group_masks(&[0, 47, 48, 95], &[48, 48]), vec![1 | 1 << 47, 1 | 1 << 47]);
+    assert_eq!(get_group_masks(&[3, 200], &[8]), vec![1 << 3]);
+}
+
+#[cfg(test)]
+fn processor_core_record(efficiency_class: u8, group: u16, mask: usize) -> Vec<u8> {
+    processor_record(RELATION_PROCESSOR_CORE, efficiency_class, group, mask)
+}
+
+#[cfg(test)]
+fn processor_record(relationship: u32, efficiency_class: u8, group: u16, mask: usize) -> Vec<u8> {
+    let size = 32 + std::mem::size_of::<GROUP_AFFINITY>();
+    let mut record = vec![0u8; size];
+    record[0..4].copy_from_slice(&relationship.to_le_bytes());
+    record[4..8].copy_from_slice(&(size as u32).to_le_bytes());
+    record[9] = efficiency_class;
+    record[30..32].copy_from_slice(&1u16.to_le_bytes());
+    record[32..32 + std::mem::size_of::<usize>()].copy_from_slice(&mask.to_le_bytes());
+    record[32 + std::mem::size_of::<usize>()..34 + std::mem::size_of::<usize>()].copy_from_slice(&group.to_le_bytes());
+    record
+}
+
+#[test]
+fn test_enumerate_efficiency_cores() {
+    // Two performance cores with SMT, then two efficiency cores.
+    let buffer = [
+        processor_core_record(1, 0, 0b0011),
+        processor_core_record(1, 0, 0b1100),
+        processor_core_record(0, 0, 0b010000),
+        processor_core_record(0, 0, 0b100000),
+    ]
+    .concat();
+    let cores = parse_processor_cores(&buffer, &[6]);
+    assert_eq!(cores.len(), 4);
+    assert_eq!(cores[0].processors, vec![0, 1]);
+    assert_eq!(get_efficiency_cores(&cores), vec![4, 5]);
+
+    // Every core is the same class on a non-hybrid CPU.
+    let buffer = [processor_core_record(0, 0, 0b01), processor_core_record(0, 0, 0b10)].concat();
+    assert!(get_efficiency_cores(&parse_processor_cores(&buffer, &[2])).is_empty());
+
+    // Processors in group 1 are numbered after all the processors in group 0.
+    let buffer = [processor_core_record(1, 0, 0b1), processor_core_record(0, 1, 0b1)].concat();
+    assert_eq!(get_efficiency_cores(&parse_processor_cores(&buffer, &[4, 4])), vec![4]);
+}
+
+
+#[cfg(test)]
+fn processor_times(idle: i64, kernel: i64, user: i64) -> SYSTEM_PROCESSOR_PERFORMANCE_INFORMATION {
+    SYSTEM_PROCESSOR_PERFORMANCE_INFORMATION {
+        IdleTime: idle,
+        KernelTime: kernel,
+        UserTime: user,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_get_core_loads() {
+    let before = [processor_times(0, 0, 0), processor_times(0, 0, 0), processor_times(100, 100, 0)];
+    // Kernel time includes idle time, so core 0 was busy for 25 of 100, and core 1 the whole time.
+    let after = [processor_times(75, 90, 10), processor_times(0, 50, 50), processor_times(100, 100, 0)];
+    assert_eq!(get_core_loads(&before, &after), vec![0.25, 1.0, 0.0]);
+
+    let loads = [0.25, 1.0, 0.0, 0.5];
+    assert_eq!(get_busiest_cores(&loads, 2, &[]), vec![1, 3]);
+    assert_eq!(get_busiest_cores(&loads, 2, &[1]), vec![3, 0]);
+    // Never every core.
+    assert_eq!(get_busiest_cores(&loads, 10, &[]), vec![1, 3, 0]);
+    assert!(get_busiest_cores(&loads, 0, &[]).is_empty());
+}
+
+
+#[test]
+fn test_package_masks() {
+    // Two sockets with 4 processors each, with the core records mixed in, like in a RelationAll buffer.
+    let buffer = [
+        processor_record(RELATION_PROCESSOR_PACKAGE, 0, 0, 0b00001111),
+        processor_core_record(0, 0, 0b0011),
+        processor_record(RELATION_PROCESSOR_PACKAGE, 0, 0, 0b11110000),
+    ]
+    .concat();
+    let masks = package_masks(&buffer);
+    assert_eq!(masks.len(), 2);
+    assert_eq!((masks[0].0, masks[0].1.Mask, masks[0].1.Group), (0, 0b00001111, 0));
+    assert_eq!((masks[1].0, masks[1].1.Mask, masks[1].1.Group), (1, 0b11110000, 0));
+    assert!(package_masks(&processor_core_record(0, 0, 0b1)).is_empty());
+
+    // Past 64 cores, each socket has its own processor group.
+    let buffer = [
+        processor_record(RELATION_PROCESSOR_PACKAGE, 0, 0, usize::MAX),
+        processor_record(RELATION_PROCESSOR_PACKAGE, 0, 1, 0b11),
+    ]
+    .concat();
+    let masks = package_masks(&buffer);
+    assert_eq!(get_group_affinity_cores(&masks[1].1, &[64, 2]), vec![64, 65]);
+}
+
+#[test]
+fn test_format_last_error() {
+    let message = format_last_error(ERROR_ACCESS_DENIED);
+    assert_ne!(message, "5");
+    assert!(message.ends_with("(5)"));
+}
+
+#[test]
+fn test_decode_module_path() {
+    let path = "C:\\Игры\\Café\\ELDEN RING\\Game\\dinput8.dll";
+    let name: Vec<u16> = path.encode_utf16().collect();
+    let decoded = decode_module_path(&name);
+    assert_eq!(decoded.to_str(), Some(path));
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+    assert_eq!(parse_duration("3").unwrap(), Duration::from_secs(3));
+    assert_eq!(parse_duration("1.5s").unwrap(), Duration::from_millis(1500));
+    assert_eq!(parse_duration("2s").unwrap(), Duration::from_secs(2));
+    assert_eq!(parse_duration("1m30s").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_duration("1m 30s").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+}
+
+#[test]
+fn test_parse_duration_invalid() {
+    assert!(parse_duration("5 potatoes").is_err());
+    assert!(parse_duration("").is_err());
+    assert!(parse_duration("-1").is_err());
+    assert!(parse_duration("-1s").is_err());
+    assert!(parse_duration("10x").is_err());
+    assert!(parse_duration("s").is_err());
+    assert!(parse_duration("1.2.3s").is_err());
+    assert!(parse_duration("nan").is_err());
+}
+
+#[test]
+fn test_parse_delay() {
+    assert_eq!(parse_delay(DurationSpec::Seconds(10.0)).unwrap(), Duration::from_secs(10));
+    assert_eq!(parse_delay(DurationSpec::Spec("24h".to_string())).unwrap(), MAX_DELAY);
+    for delay in [-1.0, f64::NAN, f64::INFINITY, f64::NEG_INFINITY, 1e12] {
+        assert!(matches!(parse_delay(DurationSpec::Seconds(delay)), Err(AffinityError::InvalidDelay(_))));
+    }
+    assert!(matches!(parse_delay(DurationSpec::Spec("25h".to_string())), Err(AffinityError::InvalidDelay(_))));
+    assert!(matches!(parse_delay(DurationSpec::Spec("-1s".to_string())), Err(AffinityError::InvalidDelay(_))));
+}
+
+#[test]
+fn test_config_validate() {
+    let system = SystemInfo { processor_count: 8, group_processor_count: 8, system_affinity_mask: 0xFF };
+    let config = |toml: &str| toml::from_str::<Config>(&format!("delay = 10\n{toml}")).unwrap();
+
+    assert!(config("exclude = [0, 1]").validate(&system).is_ok());
+    // Without strict, cores that don't exist are only a warning.
+    assert!(config("exclude = [0, 99]").validate(&system).is_ok());
+    assert!(matches!(
+        config("exclude = [0, 99]\nstrict = true").validate(&system),
+        Err(AffinityError::InvalidConfig(_))
+    ));
+    assert!(matches!(
+        config("exclude = []\npriority = \"realtime\"").validate(&system),
+        Err(AffinityError::InvalidConfig(_))
+    ));
+    assert!(matches!(
+        config("exclude = [\"0-7\"]").validate(&system),
+        Err(AffinityError::NoCoresLeft { .. })
+    ));
+    assert!(matches!(
+        config("exclude = [2, 3]\ninclude = [2, 3]").validate(&system),
+        Err(AffinityError::NoCoresLeft { .. })
+    ));
+    assert!(config("exclude = [2, 3]\ninvert = true").validate(&system).is_ok());
+    assert!(matches!(
+        config("exclude = [2]\ninvert = true\nexclude_ecores = true").validate(&system),
+        Err(AffinityError::InvalidConfig(_))
+    ));
+}
+
+
+#[test]
+fn test_count_logical_processors() {
+    assert_eq!(count_logical_processors(1, |_| 16), 16);
+    assert_eq!(count_logical_processors(2, |group| [64, 48][group as usize]), 112);
+    assert_eq!(count_logical_processors(3, |_| u32::MAX), u32::MAX);
+}
+
+
+#[test]
+fn test_validate_reapply_interval() {
+    let system = SystemInfo { processor_count: 8, group_processor_count: 8, system_affinity_mask: 0xFF };
+    let config = |reapply_interval| Config { reapply_interval: Some(reapply_interval), ..Config::default() };
+
+    assert!(config(0.5).validate(&system).is_ok());
+    for reapply_interval in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+        assert!(matches!(config(reapply_interval).validate(&system), Err(AffinityError::InvalidConfig(_))));
+    }
+}
+
+
+#[test]
+fn test_check_seconds() {
+    assert!(check_seconds("release_after", None).is_ok());
+    assert!(check_seconds("release_after", Some(0.0)).is_ok());
+    assert!(check_seconds("release_after", Some(1e9)).is_ok());
+    // Too long to fit in a Duration, which would panic in Duration::from_secs_f64.
+    for seconds in [1e300, -1.0, f64::NAN, f64::INFINITY] {
+        assert!(matches!(check_seconds("release_after", Some(seconds)), Err(AffinityError::InvalidConfig(_))));
+    }
+
+    let system = SystemInfo { processor_count: 8, group_processor_count: 8, system_affinity_mask: 0xFF };
+    let stage = Stage { at: 1e300, exclude: vec![0], include: None, mask: None };
+    assert!(Config { stages: vec![stage], ..Config::default() }.validate(&system).is_err());
+    assert!(Config { delay_jitter: Some(1e300), ..Config::default() }.validate(&system).is_err());
+}
+
+
+#[test]
+fn test_validate_dual_group() {
+    let single_group = SystemInfo { processor_count: 64, group_processor_count: 64, system_affinity_mask: usize::MAX };
+    let dual_group = SystemInfo { processor_count: 112, ..single_group.clone() };
+    let config = Config { include: Some((64..72).collect()), strict: true, ..Config::default() };
+
+    // Cores in the second group only exist on the dual group system.
+    assert!(config.validate(&dual_group).is_ok());
+    assert!(matches!(config.validate(&single_group), Err(AffinityError::InvalidConfig(_))));
+    // Cores in the current group are still checked against its mask.
+    let config = Config { include: Some(vec![0]), exclude: vec![0], ..Config::default() };
+    assert!(matches!(config.validate(&dual_group), Err(AffinityError::NoCoresLeft { .. })));
+}
+
+
+#[test]
+fn test_pin_to() {
+    assert_eq!(get_pin_mask(0), 0b1);
+    assert_eq!(get_pin_mask(5), 0b10_0000);
+    assert_eq!(get_pin_mask(usize::BITS), 0);
+
+    // The single core is set as is, without exclude.
+    let api = FakeAffinity::new(0xFF, 0xFF);
+    let options = AffinityOptions::default();
+    assert_eq!(set_processor_affinity_with(&api, Some(get_pin_mask(3)), 0, &options).unwrap(), 0b1000);
+
+    // Exclude doesn't matter, but the core still has to exist.
+    let system = SystemInfo { processor_count: 8, group_processor_count: 8, system_affinity_mask: 0xFF };
+    let config = Config { pin_to: Some(3), exclude: (0..8).collect(), ..Config::default() };
+    assert!(config.validate(&system).is_ok());
+    let config = Config { pin_to: Some(9), ..Config::default() };
+    assert!(matches!(config.validate(&system), Err(AffinityError::NoAvailableCores { requested: 0b10_0000_0000, .. })));
+    let config = Config { pin_to: Some(3), mask: Some("0xF0".to_string()), ..Config::default() };
+    assert!(matches!(config.validate(&system), Err(AffinityError::InvalidConfig(_))));
+}
+
+
+#[test]
+fn test_check_stages() {
+    let system = SystemInfo { processor_count: 8, group_processor_count: 8, system_affinity_mask: 0xFF };
+    let stage = |at: f64, exclude: &[u32]| Stage { at, exclude: exclude.to_vec(), include: None, mask: None };
+    let config = |stages: Vec<Stage>| Config { stages, ..Config::default() };
+
+    assert!(config(vec![stage(0.0, &[4, 5, 6, 7]), stage(30.0, &[0])]).validate(&system).is_ok());
+    // Two stages at the same time are fine, the later one just wins.
+    assert!(config(vec![stage(10.0, &[0]), stage(10.0, &[1])]).validate(&system).is_ok());
+    assert!(matches!(
+        config(vec![stage(30.0, &[0]), stage(10.0, &[1])]).validate(&system),
+        Err(AffinityError::InvalidConfig(_))
+    ));
+    assert!(matches!(
+        config(vec![stage(-1.0, &[0])]).validate(&system),
+        Err(AffinityError::InvalidConfig(_))
+    ));
+    assert!(matches!(
+        config(vec![stage(0.0, &[0]), stage(10.0, &[0, 1, 2, 3, 4, 5, 6, 7])]).validate(&system),
+        Err(AffinityError::NoCoresLeft { .. })
+    ));
+    let mask_stage = Stage { mask: Some("0x0".to_string()), ..stage(0.0, &[]) };
+    assert!(matches!(config(vec![mask_stage]).validate(&system), Err(AffinityError::NoCoresLeft { .. })));
+
+    // The last stage is what stays applied.
+    let settled = config(vec![stage(0.0, &[4, 5, 6, 7]), stage(30.0, &[0])]).settled();
+    assert_eq!(settled.exclude, vec![0]);
+    assert!(settled.stages.is_empty());
+}
+
+#[cfg(test)]
+fn profiles_config_file(with_default: bool) -> ConfigFile {
+    let mut toml = r#"
+        [profiles."eldenring.exe"]
+        description = "Core 1 stutters"
+        delay = 10
+        exclude = [0, 1]
+
+        [profiles."sekiro.exe"]
+        delay = 5
+        exclude = [2]
+    "#
+    .to_string();
+    if with_default {
+        toml.push_str(
+            r#"
+        [default]
+        delay = 1
+        exclude = [0]
+    "#,
+        );
+    }
+
+    toml::from_str(&toml).unwrap()
+}
+
+#[test]
+fn test_default_config() {
+    // Debug is the only way to compare configs, and it shows every field.
+    let default = format!("{:?}", Config::default());
+    assert_eq!(format!("{:?}", toml::from_str::<Config>(DEFAULT_CONFIG).unwrap()), default);
+    let serialized = toml::to_string(&Config::default()).unwrap();
+    assert_eq!(format!("{:?}", toml::from_str::<Config>(&serialized).unwrap()), default);
+}
+
+
+#[test]
+fn test_write_default_config() {
+    let path = std::env::temp_dir().join(format!("affinity-default-{}.toml", std::process::id()));
+    let _ = fs::remove_file(&path);
+    write_default_config(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), DEFAULT_CONFIG);
+    // An existing config isn't overwritten.
+    fs::write(&path, "exclude = [1]").unwrap();
+    assert_eq!(write_default_config(&path).unwrap_err().kind(), ErrorKind::AlreadyExists);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "exclude = [1]");
+    fs::remove_file(&path).unwrap();
+
+    let path = path.to_str().unwrap();
+    assert_eq!(unsafe { write_default_config_file(std::ptr::null(), 0) }, WRITE_CONFIG_INVALID_POINTER);
+    assert_eq!(unsafe { write_default_config_file(path.as_ptr(), path.len()) }, WRITE_CONFIG_WRITTEN);
+    assert_eq!(unsafe { write_default_config_file(path.as_ptr(), path.len()) }, WRITE_CONFIG_EXISTS);
+    fs::remove_file(path).unwrap();
+}
+
+
+#[test]
+fn test_select_profile() {
+    let config = select_profile(profiles_config_file(false), "eldenring.exe").unwrap();
+    assert_eq!(config.exclude, vec![0, 1]);
+    assert_eq!(config.description.as_deref(), Some("Core 1 stutters"));
+
+    let config = select_profile(profiles_config_file(false), "EldenRing.exe").unwrap();
+    assert_eq!(config.exclude, vec![0, 1]);
+
+    let config = select_profile(profiles_config_file(true), "sekiro.exe").unwrap();
+    assert_eq!(config.exclude, vec![2]);
+}
+
+#[test]
+fn test_select_profile_default() {
+    let config = select_profile(profiles_config_file(true), "armoredcore6.exe").unwrap();
+    assert_eq!(config.exclude, vec![0]);
+    assert_eq!(config.delay, Duration::from_secs(1));
+}
+
+#[test]
+fn test_select_profile_no_match() {
+    assert!(select_profile(profiles_config_file(false), "armoredcore6.exe").is_none());
+}
+
+#[test]
+fn test_check_core_indices() {
+    assert_eq!(check_core_indices("exclude", &[0, 7], 8, true).unwrap(), vec![0, 7]);
+    assert_eq!(check_core_indices("exclude", &[0, 8, 99, 3], 8, false).unwrap(), vec![0, 3]);
+    assert!(check_core_indices("exclude", &[0, 99], 8, true).is_err());
+}
+
+
+#[test]
+fn test_affinity_snapshot() {
+    let snapshot = AffinitySnapshot::new(0b1110, 0b1111, 4, 4);
+    assert_eq!(snapshot.enabled_cores, vec![1, 2, 3]);
+    assert_eq!(
+        snapshot.to_string(),
+        "Process affinity mask:\n0\n0111\nSystem affinity mask:\n0\n1111\nLogical processors: 4\nEnabled cores: 1, 2, 3"
+    );
+
+    // Two groups of 4, where the masks only cover the process's own group.
+    let snapshot = AffinitySnapshot::new(0b1110, 0b1111, 8, 4);
+    assert_eq!(
+        snapshot.to_string(),
+        "Process affinity mask:\n0\n0111\nSystem affinity mask:\n0\n1111\nLogical processors: 8\n\
+        Logical processors in this group: 4\nEnabled cores: 1, 2, 3"
+    );
+}
+
+
+#[test]
+fn test_get_cpu_set_ids() {
+    let cpu_sets = [(256, 0, 0), (257, 0, 1), (258, 0, 2), (259, 1, 0)].map(|(id, group, core)| {
+        SYSTEM_CPU_SET_INFORMATION {
+            Size: std::mem::size_of::<SYSTEM_CPU_SET_INFORMATION>() as u32,
+            Type: CPU_SET_INFORMATION,
+            Id: id,
+            Group: group,
+            LogicalProcessorIndex: core,
+            ..Default::default()
+        }
+    });
+    let buffer = unsafe {
+        std::slice::from_raw_parts(cpu_sets.as_ptr() as *const u8, std::mem::size_of_val(&cpu_sets))
+    };
+
+    assert_eq!(get_cpu_set_ids(buffer, 0b101), vec![256, 258]);
+    // Group 1 isn't covered by the mask.
+    assert_eq!(get_cpu_set_ids(buffer, 0b1), vec![256]);
+    assert_eq!(get_cpu_set_ids(buffer, 0), Vec::<u32>::new());
+}
+
+
+#[test]
+fn test_config_trigger() {
+    let config = |toml: &str| toml::from_str::<Config>(&format!("delay = 10\nexclude = []\n{toml}")).unwrap();
+
+    assert_eq!(config("").trigger(), Trigger::Delay(Duration::from_secs(10)));
+    assert_eq!(config("wait_for_idle = true").trigger(), Trigger::InputIdle { timeout: None });
+    assert_eq!(
+        config("wait_for_idle = true\nwait_for_idle_timeout = 30").trigger(),
+        Trigger::InputIdle { timeout: Some(Duration::from_secs(30)) }
+    );
+    assert_eq!(
+        config("wait_for_window = \"Main Menu\"\nwait_for_window_timeout = 60").trigger(),
+        Trigger::Window { name: "Main Menu".to_string(), timeout: Some(Duration::from_secs(60)) }
+    );
+}
+
+
+#[test]
+fn test_delay_until_idle() {
+    let config = Config { delay_until_idle: Some(20.0), ..Config::default() };
+    assert_eq!(config.trigger(), Trigger::CpuIdle { threshold: 20.0, timeout: DEFAULT_CPU_IDLE_TIMEOUT });
+    let config = Config { delay_until_idle_timeout: Some(30.0), ..config };
+    assert_eq!(config.trigger(), Trigger::CpuIdle { threshold: 20.0, timeout: Duration::from_secs(30) });
+
+    let system = SystemInfo { processor_count: 8, group_processor_count: 8, system_affinity_mask: 0xFF };
+    assert!(config.validate(&system).is_ok());
+    let invalid = Config { delay_until_idle: Some(150.0), ..Config::default() };
+    assert!(matches!(invalid.validate(&system), Err(AffinityError::InvalidConfig(_))));
+    let invalid = Config { wait_for_idle: true, ..config };
+    assert!(matches!(invalid.validate(&system), Err(AffinityError::InvalidConfig(_))));
+
+    assert_eq!(get_cpu_usage(&[0.5, 0.25, 0.25, 0.0]), 25.0);
+    assert_eq!(get_cpu_usage(&[]), 0.0);
+}
+
+
+#[test]
+fn test_window_matches() {
+    assert!(window_matches("elden ring", "ELDEN RING™", "ELDEN RING™"));
+    assert!(window_matches("UnrealWindow", "Game", "UnrealWindow"));
+    assert!(!window_matches("Unreal", "Game", "UnrealWindow"));
+    assert!(!window_matches("Launcher", "Game", "UnrealWindow"));
+}
+
+
+#[test]
+fn test_get_wait_milliseconds() {
+    assert_eq!(get_wait_milliseconds(None), INFINITE);
+    assert_eq!(get_wait_milliseconds(Some(Duration::from_millis(1500))), 1500);
+    assert_eq!(get_wait_milliseconds(Some(Duration::from_secs(u64::MAX))), INFINITE - 1);
+}
+
+
+#[test]
+fn test_parse_mask() {
+    assert_eq!(parse_mask("0xFF00").unwrap(), 0xFF00);
+    assert_eq!(parse_mask("0Xff").unwrap(), 0xFF);
+    assert_eq!(parse_mask(" 0b1111000011110000 ").unwrap(), 0b1111000011110000);
+    assert_eq!(parse_mask("0B1").unwrap(), 1);
+
+    // Wider than usize.
+    assert!(parse_mask("0x1FFFFFFFFFFFFFFFF").is_err());
+    assert!(parse_mask(&format!("0b1{}", "0".repeat(usize::BITS as usize))).is_err());
+    // No prefix, bad digits, or nothing at all.
+    for mask in ["FF00", "255", "0xFG", "0b102", "0x", "", "mask"] {
+        assert!(parse_mask(mask).is_err(), "{mask}");
+    }
+}
+
+
+#[test]
+fn test_matches_thread_name() {
+    assert!(matches_thread_name("Worker*", "Worker 3"));
+    assert!(matches_thread_name("worker*", "WORKER"));
+    assert!(matches_thread_name("*Render*", "MainRenderThread"));
+    assert!(matches_thread_name("Task*Thread", "TaskGraphThread"));
+    assert!(matches_thread_name("AudioThread", "audiothread"));
+    assert!(matches_thread_name("*", "Anything"));
+
+    assert!(!matches_thread_name("Worker*", "AudioThread"));
+    assert!(!matches_thread_name("AudioThread", "AudioThread 2"));
+    assert!(!matches_thread_name("Task*Thread", "TaskGraph"));
+    assert!(!matches_thread_name("*Thread*Thread", "Thread"));
+}
+
+
+#[test]
+fn test_resolve_preset() {
+    let core = |processors: &[u32]| ProcessorCore { efficiency_class: 0, processors: processors.to_vec() };
+    // 4 physical cores with SMT, and 3 without.
+    let smt = [core(&[0, 1]), core(&[2, 3]), core(&[4, 5]), core(&[6, 7])];
+    let no_smt = [core(&[0]), core(&[1]), core(&[2])];
+
+    assert_eq!(resolve_preset("reserve_first_core", &smt).unwrap(), 0b11);
+    assert_eq!(resolve_preset("reserve_first_core", &no_smt).unwrap(), 0b1);
+    assert_eq!(resolve_preset("reserve_smt_siblings", &smt).unwrap(), 0b10101010);
+    assert_eq!(resolve_preset("reserve_smt_siblings", &no_smt).unwrap(), 0);
+    assert_eq!(resolve_preset("reserve_half", &smt).unwrap(), 0b1111);
+    assert_eq!(resolve_preset("reserve_half", &no_smt).unwrap(), 0b1);
+    assert_eq!(resolve_preset("reserve_half", &[core(&[0])]).unwrap(), 0);
+    assert_eq!(resolve_preset("reserve_first_core", &[]).unwrap(), 0);
+
+    // Logical processors past the affinity mask are left out.
+    let wide = [core(&[usize::BITS, usize::BITS + 1]), core(&[0, 1])];
+    assert_eq!(resolve_preset("reserve_first_core", &wide).unwrap(), 0);
+
+    assert!(resolve_preset("reserve_everything", &smt).is_err());
+}
+
+
+#[test]
+fn test_get_thread_priority() {
+    assert_eq!(get_thread_priority("time_critical").unwrap(), THREAD_PRIORITY_TIME_CRITICAL);
+    assert_eq!(get_thread_priority("highest").unwrap(), THREAD_PRIORITY_HIGHEST);
+    assert_eq!(get_thread_priority("normal").unwrap(), THREAD_PRIORITY_NORMAL);
+    assert_eq!(get_thread_priority("idle").unwrap(), THREAD_PRIORITY_IDLE);
+    assert!(get_thread_priority("high").is_err());
+    assert!(get_thread_priority("Highest").is_err());
+}
+
+
+#[test]
+fn test_set_processor_affinity_empty_exclude() {
+    let api = FakeAffinity::new(0b1110, 0b1111);
+    // If the mask were set, the undone set would be used up.
+    api.undone.set(1);
+    let options = AffinityOptions::default();
+    // It is reported as left alone, not as set, so nothing runs as if the affinity had changed.
+    assert!(matches!(set_processor_affinity_with(&api, None, 0, &options), Err(AffinityError::NothingToExclude)));
+    assert_eq!(api.undone.get(), 1);
+    assert_eq!(api.process.get(), 0b1110);
+}
+
+
+#[test]
+fn test_get_pids_by_name() {
+    let processes = [(4, "System"), (100, "eldenring.exe"), (200, "explorer.exe"), (300, "EldenRing.exe")]
+        .map(|(pid, name)| (pid, name.to_string()));
+    assert_eq!(get_pids_by_name(&processes, "eldenring.exe"), vec![100, 300]);
+    assert_eq!(get_pids_by_name(&processes, "Explorer.EXE"), vec![200]);
+    assert_eq!(get_pids_by_name(&processes, "game.exe"), vec![]);
+    // The whole name has to match.
+    assert_eq!(get_pids_by_name(&processes, "eldenring"), vec![]);
+}
+
+
+#[test]
+fn test_smt_primary_mask() {
+    let core = |processors: &[u32]| ProcessorCore { efficiency_class: 0, processors: processors.to_vec() };
+    // 4 cores and 8 threads, with the siblings next to each other.
+    let cores = [core(&[0, 1]), core(&[2, 3]), core(&[4, 5]), core(&[6, 7])];
+    assert_eq!(smt_primary_mask(&cores, 0, 8), 0b01010101);
+    // The same, with the siblings numbered after every core.
+    assert_eq!(smt_primary_mask(&[core(&[0, 4]), core(&[1, 5]), core(&[2, 6]), core(&[3, 7])], 0, 8), 0b00001111);
+    // Without SMT, every logical processor is a core.
+    assert_eq!(smt_primary_mask(&[core(&[0]), core(&[1]), core(&[2]), core(&[3])], 0, 4), 0b1111);
+    assert_eq!(smt_primary_mask(&[], 0, 8), 0);
+
+    // With two groups of 4, a process in group 1 gets the cores of group 1, starting from bit 0.
+    assert_eq!(smt_primary_mask(&cores, 0, 4), 0b0101);
+    assert_eq!(smt_primary_mask(&cores, 4, 4), 0b0101);
+    let cores = [core(&[0, 1]), core(&[2, 3]), core(&[64, 65]), core(&[66, 67]), core(&[68, 69])];
+    assert_eq!(smt_primary_mask(&cores, 64, 64), 0b10101);
+    assert_eq!(smt_primary_mask(&cores, 0, 64), 0b101);
+}
+
+
+#[test]
+fn test_partial_config() {
+    let config = toml::from_str::<Config>("exclude = [2]").unwrap();
+    assert_eq!(config.exclude, vec![2]);
+    // Everything else comes from the built-in defaults.
+    let default = Config::default();
+    assert_eq!(config.delay, default.delay);
+    assert_eq!(config.include, None);
+    assert!(config.enabled);
+    assert!(config.only_if_cpu0_enabled);
+    assert!(!config.per_thread);
 
-    println!("{} {:?}", config.delay, config.exclude)
+    let config = toml::from_str::<Config>("").unwrap();
+    assert_eq!(config.exclude, default.exclude);
+    assert_eq!(config.delay, Duration::from_secs(10));
 }
@@ -0,0 +1,76 @@
+use crate::winapi::{GetLocalTime, SYSTEMTIME};
+use std::fmt::Arguments;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+// The executable name and process ID, like "[game.exe 1234]", put in front of every line once it is set.
+static PROCESS_PREFIX: OnceLock<String> = OnceLock::new();
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+// Prints the message, and appends it to the log file with a timestamp if there is one.
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::logging::write_log(format_args!($($arg)*))
+    };
+}
+pub(crate) use log;
+
+// Like log, but only when tracing is on. The message isn't even formatted otherwise.
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::logging::tracing() {
+            $crate::logging::write_log(format_args!("Trace: {}", format_args!($($arg)*)))
+        }
+    };
+}
+pub(crate) use trace;
+
+pub fn write_log(args: Arguments) {
+    let prefix = PROCESS_PREFIX.get().map(String::as_str).unwrap_or_default();
+    println!("{prefix}{args}");
+    if let Some(file) = LOG_FILE.lock().unwrap_or_else(PoisonError::into_inner).as_mut() {
+        let _ = writeln!(file, "[{}] {prefix}{args}", timestamp());
+    }
+}
+
+// Only the first call does anything, since the process doesn't change.
+pub fn set_process(name: &str, pid: u32) {
+    let _ = PROCESS_PREFIX.set(format!("[{name} {pid}] "));
+}
+
+pub fn set_trace(enabled: bool) {
+    TRACE.store(enabled, Ordering::Relaxed);
+}
+
+pub fn tracing() -> bool {
+    TRACE.load(Ordering::Relaxed)
+}
+
+// Opens the log file in append mode, so the log from every run is kept. Panics are written to the log as well, since
+// that is how most errors end up being reported.
+pub fn open_log_file(path: &Path) -> std::io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let first_open = LOG_FILE.lock().unwrap_or_else(PoisonError::into_inner).replace(file).is_none();
+    if first_open {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            write_log(format_args!("{info}"));
+            default_hook(info);
+        }));
+    }
+
+    Ok(())
+}
+
+fn timestamp() -> String {
+    let mut time = SYSTEMTIME::default();
+    unsafe { GetLocalTime(&mut time) };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        time.wYear, time.wMonth, time.wDay, time.wHour, time.wMinute, time.wSecond, time.wMilliseconds
+    )
+}
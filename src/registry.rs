@@ -0,0 +1,169 @@
+use crate::logging::log;
+use crate::winapi::*;
+use crate::{normalize_exclude, parse_exclude_spec, parse_delay, Config, DurationSpec};
+use std::io::{Error, ErrorKind};
+
+// Managed machines can set a config per executable under this key in HKEY_LOCAL_MACHINE, which users without admin
+// rights can't change. Only delay, exclude and priority can be set there, and everything else keeps its default.
+//   delay       A DWORD number of seconds, or a string like "500ms" or "1m30s".
+//   exclude     A string of cores separated by commas, like "0,1,8-15", or a DWORD with a single core.
+//   priority    A string, like "high".
+const REGISTRY_KEY: &str = r"Software\AffinityOptimizer";
+
+#[derive(Debug, PartialEq)]
+enum RegistryValue {
+    String(String),
+    Dword(u32),
+}
+
+// An open registry key, closed when it is dropped.
+struct RegistryKey(HKEY);
+
+impl RegistryKey {
+    // None if the key doesn't exist.
+    fn open(parent: HKEY, path: &str) -> std::io::Result<Option<RegistryKey>> {
+        let wide_path: Vec<u16> = path.encode_utf16().chain(std::iter::once(0)).collect();
+        let mut key = 0;
+        match unsafe { RegOpenKeyExW(parent, wide_path.as_ptr(), 0, KEY_READ, &mut key) } {
+            ERROR_SUCCESS => Ok(Some(RegistryKey(key))),
+            ERROR_FILE_NOT_FOUND => Ok(None),
+            error => Err(Error::other(format!(
+                "Unable to open registry key {path}. Last Error: {}",
+                format_last_error(error)
+            ))),
+        }
+    }
+
+    // None if the value doesn't exist. The registry APIs return the error instead of setting the last error.
+    fn get_value(&self, name: &str) -> std::io::Result<Option<RegistryValue>> {
+        let wide_name: Vec<u16> = name.encode_utf16().chain(std::iter::once(0)).collect();
+        let query_error = |error| {
+            Error::other(format!(
+                "Unable to read registry value {name}. Last Error: {}",
+                format_last_error(error)
+            ))
+        };
+
+        let mut value_type = 0;
+        let mut size = 0;
+        let null = std::ptr::null_mut();
+        match unsafe { RegQueryValueExW(self.0, wide_name.as_ptr(), null, &mut value_type, null as _, &mut size) } {
+            ERROR_SUCCESS => {}
+            ERROR_FILE_NOT_FOUND => return Ok(None),
+            error => return Err(query_error(error)),
+        }
+
+        let mut data = vec![0u8; size as usize];
+        let data_ptr = data.as_mut_ptr();
+        let error = unsafe { RegQueryValueExW(self.0, wide_name.as_ptr(), null, &mut value_type, data_ptr, &mut size) };
+        if error != ERROR_SUCCESS {
+            return Err(query_error(error));
+        }
+        data.truncate(size as usize);
+
+        parse_registry_value(name, value_type, &data).map(Some)
+    }
+}
+
+impl Drop for RegistryKey {
+    fn drop(&mut self) {
+        unsafe { RegCloseKey(self.0) };
+    }
+}
+
+// A REG_SZ can be stored with or without its null terminator, so it is trimmed either way.
+fn parse_registry_value(name: &str, value_type: u32, data: &[u8]) -> std::io::Result<RegistryValue> {
+    match value_type {
+        REG_DWORD if data.len() == 4 => Ok(RegistryValue::Dword(u32::from_le_bytes(data.try_into().unwrap()))),
+        REG_SZ => {
+            let wide: Vec<u16> = data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+            Ok(RegistryValue::String(String::from_utf16_lossy(&wide).trim_end_matches('\0').to_string()))
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Registry value {name} must be a string or a DWORD"),
+        )),
+    }
+}
+
+/// Reads the config for `exe_name` from `HKEY_LOCAL_MACHINE\Software\AffinityOptimizer\<exe_name>`, or returns None
+/// if there is no key for it.
+pub fn read_registry_config(exe_name: &str) -> std::io::Result<Option<Config>> {
+    let path = format!(r"{REGISTRY_KEY}\{exe_name}");
+    let Some(key) = RegistryKey::open(HKEY_LOCAL_MACHINE, &path)? else {
+        return Ok(None);
+    };
+
+    log!(r"Using the config from HKEY_LOCAL_MACHINE\{path}");
+    get_registry_config(key.get_value("delay")?, key.get_value("exclude")?, key.get_value("priority")?).map(Some)
+}
+
+// The config file would have the same values, so they are parsed the same way.
+fn get_registry_config(
+    delay: Option<RegistryValue>,
+    exclude: Option<RegistryValue>,
+    priority: Option<RegistryValue>,
+) -> std::io::Result<Config> {
+    let invalid = |e: &dyn std::fmt::Display| Error::new(ErrorKind::InvalidData, e.to_string());
+    let mut config = Config::default();
+    if let Some(delay) = delay {
+        let delay = match delay {
+            RegistryValue::Dword(seconds) => DurationSpec::Seconds(seconds as f64),
+            RegistryValue::String(delay) => DurationSpec::Spec(delay),
+        };
+        config.delay = parse_delay(delay).map_err(|e| invalid(&e))?;
+    }
+    if let Some(exclude) = exclude {
+        config.exclude = match exclude {
+            RegistryValue::Dword(core) => vec![core],
+            RegistryValue::String(specs) => {
+                let mut cores = vec![];
+                for spec in specs.split(',').filter(|spec| !spec.trim().is_empty()) {
+                    cores.extend(parse_exclude_spec(spec)?);
+                }
+                normalize_exclude(&cores)
+            }
+        };
+    }
+    match priority {
+        Some(RegistryValue::String(priority)) => config.priority = Some(priority),
+        Some(RegistryValue::Dword(_)) => return Err(invalid(&"Registry value priority must be a string")),
+        None => {}
+    }
+
+    Ok(config)
+}
+
+#[test]
+fn test_get_registry_config() {
+    let config = get_registry_config(
+        Some(RegistryValue::Dword(5)),
+        Some(RegistryValue::String("3, 0, 2-3".to_string())),
+        Some(RegistryValue::String("high".to_string())),
+    )
+    .unwrap();
+    assert_eq!(config.delay, std::time::Duration::from_secs(5));
+    assert_eq!(config.exclude, vec![0, 2, 3]);
+    assert_eq!(config.priority.as_deref(), Some("high"));
+
+    // Values that aren't set keep their defaults.
+    let config = get_registry_config(Some(RegistryValue::String("500ms".to_string())), None, None).unwrap();
+    assert_eq!(config.delay, std::time::Duration::from_millis(500));
+    assert_eq!(config.exclude, Config::default().exclude);
+    assert_eq!(config.priority, None);
+
+    assert!(get_registry_config(None, Some(RegistryValue::String("a".to_string())), None).is_err());
+    assert!(get_registry_config(None, None, Some(RegistryValue::Dword(1))).is_err());
+}
+
+#[test]
+fn test_parse_registry_value() {
+    assert_eq!(parse_registry_value("delay", REG_DWORD, &10u32.to_le_bytes()).unwrap(), RegistryValue::Dword(10));
+    let data: Vec<u8> = "0,1\0".encode_utf16().flat_map(u16::to_le_bytes).collect();
+    assert_eq!(
+        parse_registry_value("exclude", REG_SZ, &data).unwrap(),
+        RegistryValue::String("0,1".to_string())
+    );
+    assert!(parse_registry_value("delay", REG_DWORD, &[1, 2]).is_err());
+    assert!(parse_registry_value("delay", 3, &[]).is_err());
+}
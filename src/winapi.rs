@@ -0,0 +1,428 @@
+#![allow(clippy::upper_case_acronyms)]
+
+use std::ffi::{c_char, c_void};
+use std::ops::Deref;
+
+pub const PROCESS_SET_INFORMATION: u32 = 0x0200;
+pub const PROCESS_QUERY_INFORMATION: u32 = 0x0400;
+pub const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+pub const HIGH_PRIORITY_CLASS: u32 = 0x00000080;
+pub const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x00008000;
+pub const NORMAL_PRIORITY_CLASS: u32 = 0x00000020;
+pub const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x00004000;
+pub const IDLE_PRIORITY_CLASS: u32 = 0x00000040;
+pub const PROCESS_AFFINITY_DISABLE_AUTO_UPDATE: u32 = 0x00000000;
+pub const PROCESS_AFFINITY_ENABLE_AUTO_UPDATE: u32 = 0x00000001;
+pub const TH32CS_SNAPPROCESS: u32 = 0x00000002;
+pub const TH32CS_SNAPTHREAD: u32 = 0x00000004;
+pub const THREAD_SET_INFORMATION: u32 = 0x0020;
+pub const THREAD_QUERY_INFORMATION: u32 = 0x0040;
+pub const RELATION_PROCESSOR_CORE: u32 = 0;
+pub const RELATION_PROCESSOR_PACKAGE: u32 = 3;
+pub const CPU_SET_INFORMATION: u32 = 0;
+pub const SYSTEM_PROCESSOR_PERFORMANCE_INFORMATION_CLASS: u32 = 8;
+pub const ERROR_INSUFFICIENT_BUFFER: u32 = 122;
+pub const FORMAT_MESSAGE_IGNORE_INSERTS: u32 = 0x00000200;
+pub const FORMAT_MESSAGE_FROM_SYSTEM: u32 = 0x00001000;
+pub const PIPE_ACCESS_DUPLEX: u32 = 0x00000003;
+pub const PIPE_TYPE_BYTE: u32 = 0x00000000;
+pub const PIPE_READMODE_BYTE: u32 = 0x00000000;
+pub const PIPE_WAIT: u32 = 0x00000000;
+pub const PIPE_REJECT_REMOTE_CLIENTS: u32 = 0x00000008;
+pub const ERROR_BROKEN_PIPE: u32 = 109;
+pub const ERROR_PIPE_CONNECTED: u32 = 535;
+pub const PROCESS_POWER_THROTTLING: u32 = 4;
+pub const PROCESS_POWER_THROTTLING_CURRENT_VERSION: u32 = 1;
+pub const PROCESS_POWER_THROTTLING_EXECUTION_SPEED: u32 = 0x1;
+pub const INFINITE: u32 = 0xFFFFFFFF;
+pub const WAIT_TIMEOUT: u32 = 258;
+pub const THREAD_PRIORITY_IDLE: i32 = -15;
+pub const THREAD_PRIORITY_LOWEST: i32 = -2;
+pub const THREAD_PRIORITY_BELOW_NORMAL: i32 = -1;
+pub const THREAD_PRIORITY_NORMAL: i32 = 0;
+pub const THREAD_PRIORITY_ABOVE_NORMAL: i32 = 1;
+pub const THREAD_PRIORITY_HIGHEST: i32 = 2;
+pub const THREAD_PRIORITY_TIME_CRITICAL: i32 = 15;
+pub const ERROR_ACCESS_DENIED: u32 = 5;
+pub const JOB_OBJECT_BASIC_LIMIT_INFORMATION_CLASS: u32 = 2;
+pub const JOB_OBJECT_LIMIT_AFFINITY: u32 = 0x00000010;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct HANDLE(*const c_void);
+
+impl HANDLE {
+    pub fn null() -> HANDLE {
+        HANDLE(std::ptr::null())
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.0 != 0 as _ && self.0 != -1 as _
+    }
+
+    pub fn as_ptr(&self) -> *const c_void {
+        self.0
+    }
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct THREADENTRY32 {
+    pub dwSize: u32,
+    pub cntUsage: u32,
+    pub th32ThreadID: u32,
+    pub th32OwnerProcessID: u32,
+    pub tpBasePri: i32,
+    pub tpDeltaPri: i32,
+    pub dwFlags: u32,
+}
+
+#[repr(C)]
+pub struct PROCESSENTRY32W {
+    pub dwSize: u32,
+    pub cntUsage: u32,
+    pub th32ProcessID: u32,
+    pub th32DefaultHeapID: usize,
+    pub th32ModuleID: u32,
+    pub cntThreads: u32,
+    pub th32ParentProcessID: u32,
+    pub pcPriClassBase: i32,
+    pub dwFlags: u32,
+    pub szExeFile: [u16; 260],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct GROUP_AFFINITY {
+    pub Mask: usize,
+    pub Group: u16,
+    pub Reserved: [u16; 3],
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct SYSTEMTIME {
+    pub wYear: u16,
+    pub wMonth: u16,
+    pub wDayOfWeek: u16,
+    pub wDay: u16,
+    pub wHour: u16,
+    pub wMinute: u16,
+    pub wSecond: u16,
+    pub wMilliseconds: u16,
+}
+
+#[repr(C)]
+pub struct SYSTEM_INFO {
+    pub wProcessorArchitecture: u16,
+    pub wReserved: u16,
+    pub dwPageSize: u32,
+    pub lpMinimumApplicationAddress: *mut c_void,
+    pub lpMaximumApplicationAddress: *mut c_void,
+    pub dwActiveProcessorMask: usize,
+    pub dwNumberOfProcessors: u32,
+    pub dwProcessorType: u32,
+    pub dwAllocationGranularity: u32,
+    pub wProcessorLevel: u16,
+    pub wProcessorRevision: u16,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct JOBOBJECT_BASIC_LIMIT_INFORMATION {
+    pub PerProcessUserTimeLimit: i64,
+    pub PerJobUserTimeLimit: i64,
+    pub LimitFlags: u32,
+    pub MinimumWorkingSetSize: usize,
+    pub MaximumWorkingSetSize: usize,
+    pub ActiveProcessLimit: u32,
+    pub Affinity: usize,
+    pub PriorityClass: u32,
+    pub SchedulingClass: u32,
+}
+
+#[repr(C)]
+pub struct PROCESS_POWER_THROTTLING_STATE {
+    pub Version: u32,
+    pub ControlMask: u32,
+    pub StateMask: u32,
+}
+
+// SetProcessInformation is only in kernel32 on Windows 8 and newer, so it is looked up at runtime instead of linked,
+// which would stop the dll from loading at all on older versions.
+pub type SetProcessInformationFn = unsafe extern "system" fn(
+    hProcess: HANDLE,
+    ProcessInformationClass: u32,
+    ProcessInformation: *const c_void,
+    ProcessInformationSize: u32,
+) -> bool;
+
+// The times are in 100ns units since boot, and KernelTime includes IdleTime.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct SYSTEM_PROCESSOR_PERFORMANCE_INFORMATION {
+    pub IdleTime: i64,
+    pub KernelTime: i64,
+    pub UserTime: i64,
+    pub DpcTime: i64,
+    pub InterruptTime: i64,
+    pub InterruptCount: u32,
+}
+
+// The CPU Set of one logical processor, flattened from the CpuSet member of the union in the Windows headers.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct SYSTEM_CPU_SET_INFORMATION {
+    pub Size: u32,
+    pub Type: u32,
+    pub Id: u32,
+    pub Group: u16,
+    pub LogicalProcessorIndex: u8,
+    pub CoreIndex: u8,
+    pub LastLevelCacheIndex: u8,
+    pub NumaNodeIndex: u8,
+    pub EfficiencyClass: u8,
+    pub AllFlags: u8,
+    pub Reserved: u32,
+    pub AllocationTag: u64,
+}
+
+// CPU Sets are only in kernel32 on Windows 10 and newer, so these are looked up at runtime like SetProcessInformation.
+pub type GetSystemCpuSetInformationFn = unsafe extern "system" fn(
+    Information: *mut u8,
+    BufferLength: u32,
+    ReturnedLength: *mut u32,
+    Process: HANDLE,
+    Flags: u32,
+) -> bool;
+pub type SetProcessDefaultCpuSetsFn =
+    unsafe extern "system" fn(Process: HANDLE, CpuSetIds: *const u32, CpuSetIdCount: u32) -> bool;
+// Windows 10 1607 and newer. The description is allocated by Windows, and has to be freed with LocalFree.
+pub type GetThreadDescriptionFn = unsafe extern "system" fn(hThread: HANDLE, ppszThreadDescription: *mut *mut u16) -> i32;
+
+pub struct OwnedHandle(HANDLE);
+
+impl OwnedHandle {
+    pub unsafe fn new(handle: HANDLE) -> OwnedHandle {
+        Self(handle)
+    }
+}
+
+impl Deref for OwnedHandle {
+    type Target = HANDLE;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for OwnedHandle {
+    fn drop(&mut self) {
+        // is_valid is false for -1, which is also the GetCurrentProcess pseudo-handle, so that never gets closed.
+        if self.is_valid() {
+            unsafe { CloseHandle(self.0) }
+        }
+    }
+}
+
+#[link(name = "kernel32", kind = "raw-dylib")]
+extern "system" {
+    #[cfg(feature = "Console")]
+    pub fn AllocConsole() -> u32;
+    #[cfg(feature = "Console")]
+    pub fn AttachConsole(dwProcessId: u32) -> u32;
+    pub fn CloseHandle(object: HANDLE);
+    pub fn GetCurrentProcess() -> HANDLE;
+    pub fn GetCurrentProcessId() -> u32;
+    pub fn GetCurrentThread() -> HANDLE;
+    pub fn CreateNamedPipeW(
+        lpName: *const u16,
+        dwOpenMode: u32,
+        dwPipeMode: u32,
+        nMaxInstances: u32,
+        nOutBufferSize: u32,
+        nInBufferSize: u32,
+        nDefaultTimeOut: u32,
+        lpSecurityAttributes: *const c_void,
+    ) -> HANDLE;
+    pub fn ConnectNamedPipe(hNamedPipe: HANDLE, lpOverlapped: *mut c_void) -> bool;
+    pub fn DisconnectNamedPipe(hNamedPipe: HANDLE) -> bool;
+    pub fn ReadFile(
+        hFile: HANDLE,
+        lpBuffer: *mut u8,
+        nNumberOfBytesToRead: u32,
+        lpNumberOfBytesRead: *mut u32,
+        lpOverlapped: *mut c_void,
+    ) -> bool;
+    pub fn WriteFile(
+        hFile: HANDLE,
+        lpBuffer: *const u8,
+        nNumberOfBytesToWrite: u32,
+        lpNumberOfBytesWritten: *mut u32,
+        lpOverlapped: *mut c_void,
+    ) -> bool;
+    pub fn GetSystemInfo(lpSystemInfo: *mut SYSTEM_INFO);
+    pub fn GetLocalTime(lpSystemTime: *mut SYSTEMTIME);
+    pub fn GetModuleHandleW(lpModuleName: *const u16) -> usize;
+    pub fn GetProcAddress(hModule: usize, lpProcName: *const c_char) -> *const c_void;
+    pub fn GetModuleFileNameW(hModule: usize, lpFilename: *mut u16, nSize: u32) -> u32;
+    pub fn GetSystemDirectoryW(lpBuffer: *mut u16, uSize: u32) -> u32;
+    pub fn OpenProcess(dwDesiredAccess: u32, bInheritHandle: bool, dwProcessId: u32) -> HANDLE;
+    pub fn GetProcessAffinityMask(
+        hProcess: HANDLE,
+        lpProcessAffinityMask: *mut usize,
+        lpSystemAffinityMask: *mut usize,
+    ) -> bool;
+    pub fn SetProcessAffinityMask(
+        hProcess: HANDLE,
+        lpProcessAffinityMask: usize,
+    ) -> bool;
+    pub fn SetProcessAffinityUpdateMode(hProcess: HANDLE, dwFlags: u32) -> bool;
+    pub fn QueryProcessAffinityUpdateMode(hProcess: HANDLE, lpdwFlags: *mut u32) -> bool;
+    pub fn GetPriorityClass(hProcess: HANDLE) -> u32;
+    pub fn SetPriorityClass(hProcess: HANDLE, dwPriorityClass: u32) -> bool;
+    pub fn CreateToolhelp32Snapshot(dwFlags: u32, th32ProcessID: u32) -> HANDLE;
+    pub fn Thread32First(hSnapshot: HANDLE, lpte: *mut THREADENTRY32) -> bool;
+    pub fn Thread32Next(hSnapshot: HANDLE, lpte: *mut THREADENTRY32) -> bool;
+    pub fn Process32FirstW(hSnapshot: HANDLE, lppe: *mut PROCESSENTRY32W) -> bool;
+    pub fn Process32NextW(hSnapshot: HANDLE, lppe: *mut PROCESSENTRY32W) -> bool;
+    pub fn OpenThread(dwDesiredAccess: u32, bInheritHandle: bool, dwThreadId: u32) -> HANDLE;
+    pub fn LocalFree(hMem: *mut c_void) -> *mut c_void;
+    pub fn SetThreadPriority(hThread: HANDLE, nPriority: i32) -> bool;
+    pub fn SetThreadIdealProcessor(hThread: HANDLE, dwIdealProcessor: u32) -> u32;
+    pub fn SetThreadAffinityMask(hThread: HANDLE, dwThreadAffinityMask: usize) -> usize;
+    pub fn GetActiveProcessorGroupCount() -> u16;
+    pub fn GetNumaHighestNodeNumber(HighestNodeNumber: *mut u32) -> bool;
+    pub fn GetNumaNodeProcessorMaskEx(Node: u16, ProcessorMask: *mut GROUP_AFFINITY) -> bool;
+    pub fn GetActiveProcessorCount(GroupNumber: u16) -> u32;
+    pub fn SetThreadGroupAffinity(
+        hThread: HANDLE,
+        GroupAffinity: *const GROUP_AFFINITY,
+        PreviousGroupAffinity: *mut GROUP_AFFINITY,
+    ) -> bool;
+    pub fn GetThreadGroupAffinity(hThread: HANDLE, GroupAffinity: *mut GROUP_AFFINITY) -> bool;
+    pub fn FormatMessageW(
+        dwFlags: u32,
+        lpSource: *const c_void,
+        dwMessageId: u32,
+        dwLanguageId: u32,
+        lpBuffer: *mut u16,
+        nSize: u32,
+        Arguments: *const c_void,
+    ) -> u32;
+    pub fn GetLogicalProcessorInformationEx(
+        RelationshipType: u32,
+        Buffer: *mut u8,
+        ReturnedLength: *mut u32,
+    ) -> bool;
+    pub fn IsProcessInJob(ProcessHandle: HANDLE, JobHandle: HANDLE, Result: *mut i32) -> bool;
+    pub fn QueryInformationJobObject(
+        hJob: HANDLE,
+        JobObjectInformationClass: u32,
+        lpJobObjectInformation: *mut c_void,
+        cbJobObjectInformationLength: u32,
+        lpReturnLength: *mut u32,
+    ) -> bool;
+}
+
+#[cfg(feature = "Registry")]
+pub type HKEY = usize;
+// Predefined keys are sign extended, like any other handle.
+#[cfg(feature = "Registry")]
+pub const HKEY_LOCAL_MACHINE: HKEY = 0x80000002u32 as i32 as isize as usize;
+#[cfg(feature = "Registry")]
+pub const KEY_READ: u32 = 0x20019;
+#[cfg(feature = "Registry")]
+pub const REG_SZ: u32 = 1;
+#[cfg(feature = "Registry")]
+pub const REG_DWORD: u32 = 4;
+#[cfg(feature = "Registry")]
+pub const ERROR_SUCCESS: u32 = 0;
+#[cfg(feature = "Registry")]
+pub const ERROR_FILE_NOT_FOUND: u32 = 2;
+
+pub const EVENTLOG_INFORMATION_TYPE: u16 = 4;
+
+#[link(name = "advapi32", kind = "raw-dylib")]
+extern "system" {
+    #[cfg(feature = "Registry")]
+    pub fn RegOpenKeyExW(
+        hKey: HKEY,
+        lpSubKey: *const u16,
+        ulOptions: u32,
+        samDesired: u32,
+        phkResult: *mut HKEY,
+    ) -> u32;
+    #[cfg(feature = "Registry")]
+    pub fn RegQueryValueExW(
+        hKey: HKEY,
+        lpValueName: *const u16,
+        lpReserved: *mut u32,
+        lpType: *mut u32,
+        lpData: *mut u8,
+        lpcbData: *mut u32,
+    ) -> u32;
+    #[cfg(feature = "Registry")]
+    pub fn RegCloseKey(hKey: HKEY) -> u32;
+    pub fn RegisterEventSourceW(lpUNCServerName: *const u16, lpSourceName: *const u16) -> HANDLE;
+    pub fn ReportEventW(
+        hEventLog: HANDLE,
+        wType: u16,
+        wCategory: u16,
+        dwEventID: u32,
+        lpUserSid: *const c_void,
+        wNumStrings: u16,
+        dwDataSize: u32,
+        lpStrings: *const *const u16,
+        lpRawData: *const c_void,
+    ) -> i32;
+    pub fn DeregisterEventSource(hEventLog: HANDLE) -> i32;
+}
+
+#[link(name = "ntdll", kind = "raw-dylib")]
+extern "system" {
+    pub fn NtQuerySystemInformation(
+        SystemInformationClass: u32,
+        SystemInformation: *mut c_void,
+        SystemInformationLength: u32,
+        ReturnLength: *mut u32,
+    ) -> i32;
+}
+
+pub type HWND = isize;
+pub type LPARAM = isize;
+pub type WNDENUMPROC = unsafe extern "system" fn(hwnd: HWND, lParam: LPARAM) -> i32;
+
+#[link(name = "user32", kind = "raw-dylib")]
+extern "system" {
+    pub fn WaitForInputIdle(hProcess: HANDLE, dwMilliseconds: u32) -> u32;
+    pub fn EnumWindows(lpEnumFunc: WNDENUMPROC, lParam: LPARAM) -> i32;
+    pub fn GetWindowThreadProcessId(hWnd: HWND, lpdwProcessId: *mut u32) -> u32;
+    pub fn GetWindowTextW(hWnd: HWND, lpString: *mut u16, nMaxCount: i32) -> i32;
+    pub fn GetClassNameW(hWnd: HWND, lpClassName: *mut u16, nMaxCount: i32) -> i32;
+}
+
+// Turns a GetLastError code into the message Windows has for it, like "Access is denied. (5)". Falls back to just the
+// code if Windows doesn't have a message for it.
+pub fn format_last_error(code: u32) -> String {
+    let mut buffer = [0u16; 512];
+    let length = unsafe {
+        FormatMessageW(
+            FORMAT_MESSAGE_FROM_SYSTEM | FORMAT_MESSAGE_IGNORE_INSERTS,
+            std::ptr::null(),
+            code,
+            0,
+            buffer.as_mut_ptr(),
+            buffer.len() as u32,
+            std::ptr::null(),
+        )
+    } as usize;
+
+    let message = String::from_utf16_lossy(&buffer[..length.min(buffer.len())]);
+    let message = message.trim();
+    if message.is_empty() {
+        return format!("{code:X}");
+    }
+
+    format!("{message} ({code:X})")
+}